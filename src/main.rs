@@ -8,26 +8,39 @@ fn main() {
   let args: Vec<String> = env::args().collect();
 
   if args.len() < 2 {
-    eprintln!("Usage: rc <file.riff> [-o <output_path>]");
+    eprintln!("{}", USAGE);
     std::process::exit(2);
   }
 
-  println!("[_] rc {}\n", env!("CARGO_PKG_VERSION"));
-
-  println!("[i] Input: {}", args[1]);
-
   let input_path = &args[1];
   let mut output_path: Option<PathBuf> = None;
-
-  // Parse -o flag if present
-  if args.len() > 2 && args[2] == "-o" && args.len() > 3 {
-    output_path = Some(PathBuf::from(&args[3]));
+  let mut max_depth: Option<usize> = None;
+
+  // Parse the flags after the input file
+  let mut flags = args[2..].iter();
+  while let Some(flag) = flags.next() {
+    match flag.as_str() {
+      "-o" => match flags.next() {
+        Some(path) => output_path = Some(PathBuf::from(path)),
+        None => usage_error("-o needs an output path"),
+      },
+      "--max-depth" => match flags.next().and_then(|n| n.parse().ok()).filter(|&n: &usize| n > 0) {
+        Some(n) => max_depth = Some(n),
+        None => usage_error("--max-depth needs a positive whole number"),
+      },
+      _ => usage_error(&format!("unknown option '{}'", flag)),
+    }
   }
 
+  println!("[_] rc {}\n", env!("CARGO_PKG_VERSION"));
+
+  println!("[i] Input: {}", args[1]);
+
   let code = fs::read_to_string(input_path).expect("[x] failed to read input file");
 
-  // Validate syntax before compilation
-  if let Err(e) = validate_rf_syntax(&code, input_path) {
+  // Validate syntax before compilation, following imports into other files
+  let mut program = Program::new(input_path);
+  if let Err(e) = program.load(0, input_path, &code, &mut vec![0]) {
     eprintln!("{}", e);
     std::process::exit(1);
   }
@@ -69,7 +82,7 @@ fn main() {
 
   let rs_path = temp_dir.join(format!("{}.rs", file_name));
 
-  let generated = generate_rust_program(&code);
+  let generated = generate_rust_program(&code, &program, max_depth);
   fs::write(&rs_path, generated).expect("[x] failed to write generated rust file");
 
   print!("[i] Compiling... ");
@@ -94,14 +107,116 @@ fn main() {
   println!("[i] Generated executable at {}", exe_path.to_string_lossy());
 }
 
-/// Validate RF syntax before compilation
-fn validate_rf_syntax(code: &str, filename: &str) -> Result<(), String> {
+const USAGE: &str = "Usage: rc <file.riff> [-o <output_path>] [--max-depth <calls>]";
+
+fn usage_error(msg: &str) -> ! {
+  eprintln!("[x] {}\n{}", msg, USAGE);
+  std::process::exit(2);
+}
+
+/// An `#"path"` import found while validating a file
+struct Import {
+  path: String,
+  line: usize,
+  col: usize,
+}
+
+/// Every file that makes up the program, collected by following imports
+struct Program {
+  /// (canonical path, name used in diagnostics) of each file, the main file first
+  files: Vec<(PathBuf, String)>,
+  /// code of each imported file, file k is `modules[k - 1]`
+  modules: Vec<String>,
+  /// (importing file, path as written, imported file)
+  imports: Vec<(usize, String, usize)>,
+}
+
+impl Program {
+  fn new(input_path: &str) -> Self {
+    let canonical = fs::canonicalize(input_path).unwrap_or_else(|_| PathBuf::from(input_path));
+    Program { files: vec![(canonical, input_path.to_string())], modules: Vec::new(), imports: Vec::new() }
+  }
+
+  /// Validate file `index` and load everything it imports, paths are relative to the importing file.
+  /// `stack` holds the files currently being loaded, so an import of one of them is a cycle.
+  fn load(&mut self, index: usize, name: &str, code: &str, stack: &mut Vec<usize>) -> Result<(), String> {
+    let dir = Path::new(name).parent().unwrap_or_else(|| Path::new(""));
+    for import in validate_rf_syntax(code, name)? {
+      let at = format!("{}:{}:{}", name, import.line, import.col);
+      let joined = dir.join(&import.path);
+      let canonical = fs::canonicalize(&joined)
+        .map_err(|e| format!("{}: error: cannot import '{}'\n  {}", at, import.path, e))?;
+      let target = match self.files.iter().position(|(p, _)| *p == canonical) {
+        Some(t) => {
+          if let Some(start) = stack.iter().position(|&s| s == t) {
+            let mut chain: Vec<&str> = stack[start..].iter().map(|&s| self.files[s].1.as_str()).collect();
+            chain.push(&self.files[t].1);
+            return Err(format!("{}: error: import cycle\n  {}", at, chain.join(" -> ")));
+          }
+          t
+        }
+        None => {
+          let import_name = joined.to_string_lossy().to_string();
+          let import_code = fs::read_to_string(&joined)
+            .map_err(|e| format!("{}: error: cannot import '{}'\n  {}", at, import.path, e))?;
+          self.files.push((canonical, import_name.clone()));
+          self.modules.push(import_code.clone());
+          let t = self.files.len() - 1;
+          stack.push(t);
+          self.load(t, &import_name, &import_code, stack)?;
+          stack.pop();
+          t
+        }
+      };
+      self.imports.push((index, import.path, target));
+    }
+    Ok(())
+  }
+}
+
+/// Index just past the string literal whose text starts at `i` (or the end of the code)
+fn skip_string(bytes: &[u8], mut i: usize) -> usize {
+  while i < bytes.len() {
+    match bytes[i] {
+      b'\\' => i += 2,
+      b'"' => return i + 1,
+      b'{' => i = skip_embedded(bytes, i + 1),
+      _ => i += 1,
+    }
+  }
+  bytes.len()
+}
+
+/// Index just past the '}' closing an embedded `{expr}` whose code starts at `i`
+fn skip_embedded(bytes: &[u8], mut i: usize) -> usize {
+  let mut depth = 0;
+  while i < bytes.len() {
+    match bytes[i] {
+      b'"' => i = skip_string(bytes, i + 1),
+      b'{' => {
+        depth += 1;
+        i += 1;
+      }
+      b'}' if depth == 0 => return i + 1,
+      b'}' => {
+        depth -= 1;
+        i += 1;
+      }
+      _ => i += 1,
+    }
+  }
+  bytes.len()
+}
+
+/// Validate RF syntax before compilation, returning the file's imports
+fn validate_rf_syntax(code: &str, filename: &str) -> Result<Vec<Import>, String> {
   let mut line_num = 1;
   let mut col_num = 1;
   let mut i = 0;
   let bytes = code.as_bytes();
   let mut brace_stack: Vec<(usize, usize)> = Vec::new(); // (line, col) of opening braces
   let mut bracket_stack: Vec<(usize, usize)> = Vec::new(); // (line, col) of opening brackets
+  let mut imports = Vec::new();
 
   while i < bytes.len() {
     let c = bytes[i] as char;
@@ -120,6 +235,56 @@ fn validate_rf_syntax(code: &str, filename: &str) -> Result<(), String> {
       i += 1;
       continue;
     }
+    if c == '"' {
+      // Skip string literals so braces and comment markers inside them are ignored,
+      // including `{expr}` pieces whose code may hold strings of its own
+      let end = skip_string(bytes, i + 1);
+      for &b in &bytes[i + 1..end] {
+        if b == b'\n' {
+          line_num += 1;
+          col_num = 1;
+        } else {
+          col_num += 1;
+        }
+      }
+      i = end;
+      continue;
+    }
+    if c == '<' && i + 1 < bytes.len() && (bytes[i + 1] as char) == '@' {
+      // Skip a multiline comment, which may contain nested <@ ... @> pairs
+      let open_line = line_num;
+      let mut depth = 1;
+      i += 2;
+      col_num += 1;
+      while i < bytes.len() && depth > 0 {
+        let cc = bytes[i] as char;
+        let next = bytes.get(i + 1).map(|&b| b as char);
+        if cc == '<' && next == Some('@') {
+          depth += 1;
+          i += 2;
+          col_num += 2;
+        } else if cc == '@' && next == Some('>') {
+          depth -= 1;
+          i += 2;
+          col_num += 2;
+        } else {
+          if cc == '\n' {
+            line_num += 1;
+            col_num = 1;
+          } else {
+            col_num += 1;
+          }
+          i += 1;
+        }
+      }
+      if depth > 0 {
+        return Err(format!(
+          "{}:{}:{}: error: unterminated '<@'\n  Multiline comment at line {} never closed",
+          filename, line_num, col_num, open_line
+        ));
+      }
+      continue;
+    }
     if c == '@' {
       // Skip until end of line
       while i < bytes.len() && (bytes[i] as char) != '\n' {
@@ -128,6 +293,41 @@ fn validate_rf_syntax(code: &str, filename: &str) -> Result<(), String> {
       continue;
     }
 
+    // Imports: `#"path"`, but not the `.#` console read
+    if c == '#' && (i == 0 || bytes[i - 1] != b'.') {
+      let (line, col) = (line_num, col_num - 1);
+      let mut j = i + 1;
+      while j < bytes.len() && (bytes[j] == b' ' || bytes[j] == b'\t') {
+        j += 1;
+      }
+      if bytes.get(j) != Some(&b'"') {
+        return Err(format!(
+          "{}:{}:{}: error: expected a quoted path after '#'\n  Imports are written #\"file.riff\"",
+          filename, line, col
+        ));
+      }
+      let end = match code[j + 1..].find(['"', '\n']) {
+        Some(e) if code[j + 1 + e..].starts_with('"') => j + 1 + e,
+        _ => {
+          return Err(format!(
+            "{}:{}:{}: error: unterminated import path",
+            filename, line, col
+          ))
+        }
+      };
+      let path = &code[j + 1..end];
+      if path.is_empty() || path.contains(['\\', '{', '}']) {
+        return Err(format!(
+          "{}:{}:{}: error: invalid import path \"{}\"\n  Import paths are plain text without escapes or {{}}",
+          filename, line, col, path
+        ));
+      }
+      imports.push(Import { path: path.to_string(), line, col });
+      col_num += end - i;
+      i = end + 1;
+      continue;
+    }
+
     // Check for brace matching
     if c == '{' {
       brace_stack.push((line_num, col_num));
@@ -183,17 +383,32 @@ fn validate_rf_syntax(code: &str, filename: &str) -> Result<(), String> {
     ));
   }
 
-  Ok(())
+  Ok(imports)
 }
 
 /// Produce a standalone Rust program string that embeds a small RF interpreter and the code.
-fn generate_rust_program(code: &str) -> String {
-  // escape backslashes, quotes, CR, and newlines so the generated Rust string literal stays valid
-  let escaped_code: String = code
-    .replace("\\", "\\\\")
-    .replace('"', "\\\"")
-    .replace("\r", "")
-    .replace("\n", "\\n");
+/// `max_depth` replaces the runtime's default limit on nested function calls.
+fn generate_rust_program(code: &str, program: &Program, max_depth: Option<usize>) -> String {
+  // Debug formatting gives a valid Rust string literal for any code, backslashes included
+  let code_literal = format!("{:?}", code.replace("\r", ""));
+  let modules: Vec<String> = program.files[1..]
+    .iter()
+    .zip(&program.modules)
+    .map(|((_, name), code)| format!("({:?}, {:?})", name, code.replace("\r", "")))
+    .collect();
+  let imports: Vec<String> = program
+    .imports
+    .iter()
+    .map(|(from, path, to)| format!("({}, {:?}, {})", from, path, to))
+    .collect();
   let template: &str = include_str!("../template/main.rs");
-  template.replace("__RF_CODE_ESCAPED__", &escaped_code)
+  // placeholders are filled from the bottom of the template up, so text inserted
+  // for one of them is never mistaken for a placeholder above it
+  let mut generated = template
+    .replacen("/*__RF_IMPORTS__*/", &imports.join(", "), 1)
+    .replacen("/*__RF_MODULES__*/", &modules.join(", "), 1);
+  if let Some(depth) = max_depth {
+    generated = generated.replacen("/*__RF_MAX_DEPTH__*/1000", &depth.to_string(), 1);
+  }
+  generated.replacen("\"__RF_CODE_ESCAPED__\"", &code_literal, 1)
 }
\ No newline at end of file
@@ -1,4 +1,9 @@
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::rc::Rc;
 
 fn main() {
   // embedded Riff code is replaced at __RF_CODE_ESCAPED__
@@ -9,562 +14,2680 @@ fn main() {
   }
 }
 
+/// Deepest nesting of function calls, set with `rc --max-depth` and overridden by RIFF_MAX_DEPTH
+const DEFAULT_MAX_DEPTH: usize = /*__RF_MAX_DEPTH__*/1000;
+/// Native stack for the interpreter thread: a fixed part plus room for each level the evaluator
+/// recurses through (a call, an if or loop body, a nested expression). A level measured at most
+/// 1.2 KiB in an optimised build, so each gets over three times that
+const STACK_BASE: usize = 16 << 20;
+const STACK_PER_LEVEL: usize = 4 << 10;
+/// Lines of a call stack shown from its most recent and its outermost end
+const TRACE_RECENT: usize = 10;
+const TRACE_OUTER: usize = 3;
+
+/// Files brought in with `#"path"`: (name used in errors, code), filled in by rc.
+/// File 0 is the main program and file k is `MODULES[k - 1]`.
+const MODULES: &[(&str, &str)] = &[/*__RF_MODULES__*/];
+/// (importing file, path as written, imported file), resolved by rc
+const IMPORTS: &[(usize, &str, usize)] = &[/*__RF_IMPORTS__*/];
+
 #[derive(Debug, Clone)]
 enum Val {
     Int(i64),
+    Big(BigInt),
+    Float(f64),
+    Bool(bool),
     Str(String),
     List(Vec<Val>),
+    Map(BTreeMap<Key, Val>),
+    Func(Rc<Closure>),
+}
+
+/// A function as a value: a named function or `$[a]{...}`, with the variables it captured
+#[derive(Debug)]
+struct Closure {
+    func: Rc<Func>,
+    captured: HashMap<String, Val>,
+}
+
+impl Closure {
+    /// How errors refer to the function: its name, or its parameters when it has none
+    fn label(&self) -> String {
+        match &self.func.name {
+            Some(name) => name.clone(),
+            None => format!("$[{}]", self.func.params.join(",")),
+        }
+    }
+}
+
+/// Map keys: the values that can be ordered and compared exactly
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+}
+
+impl Key {
+    fn from_val(v: &Val) -> Result<Key, String> {
+        match v {
+            Val::Bool(b) => Ok(Key::Bool(*b)),
+            Val::Int(i) => Ok(Key::Int(*i)),
+            Val::Str(s) => Ok(Key::Str(s.clone())),
+            _ => Err(format!("Map keys must be strings, booleans or integers that fit in 64 bits, got {} {}", v.type_name(), v.repr())),
+        }
+    }
+    fn to_val(&self) -> Val {
+        match self {
+            Key::Bool(b) => Val::Bool(*b),
+            Key::Int(i) => Val::Int(*i),
+            Key::Str(s) => Val::Str(s.clone()),
+        }
+    }
 }
 
 impl Val {
     fn as_i64(&self) -> i64 {
         match self {
             Val::Int(i) => *i,
+            Val::Big(b) => b.to_i64().unwrap_or(if b.neg { i64::MIN } else { i64::MAX }),
+            Val::Float(f) => *f as i64,
+            Val::Bool(b) => *b as i64,
             Val::Str(s) => s.parse().unwrap_or(0),
             Val::List(v) => v.iter().map(|x| x.as_i64()).sum(),
+            Val::Map(_) | Val::Func(_) => 0,
+        }
+    }
+    fn as_f64(&self) -> f64 {
+        match self {
+            Val::Float(f) => *f,
+            Val::Big(b) => b.to_f64(),
+            _ => self.as_i64() as f64,
         }
     }
     fn as_string(&self) -> String {
         match self {
             Val::Int(i) => i.to_string(),
+            Val::Big(b) => b.to_string(),
+            // always keep a decimal point so floats stay recognisable: 2.0, 0.5, 1e20
+            Val::Float(f) => format!("{:?}", f),
+            Val::Bool(b) => if *b { "?+".to_string() } else { "?-".to_string() },
             Val::Str(s) => s.clone(),
             Val::List(v) => {
                 let parts: Vec<String> = v.iter().map(|x| x.as_string()).collect();
                 format!("[{}]", parts.join(","))
             }
+            // keys are kept sorted so printing is deterministic
+            Val::Map(m) => {
+                let parts: Vec<String> = m.iter().map(|(k, v)| format!("{}:{}", k.to_val().as_string(), v.as_string())).collect();
+                format!("{{{}}}", parts.join(","))
+            }
+            Val::Func(c) => format!("${}[{}]", c.func.name.as_deref().unwrap_or(""), c.func.params.join(",")),
+        }
+    }
+    /// Like `as_string` but strings are quoted, for error messages
+    fn repr(&self) -> String {
+        match self {
+            Val::Str(s) => format!("\"{}\"", s),
+            _ => self.as_string(),
+        }
+    }
+    fn type_name(&self) -> &'static str {
+        match self {
+            Val::Int(_) | Val::Big(_) => "integer",
+            Val::Float(_) => "float",
+            Val::Bool(_) => "boolean",
+            Val::Str(_) => "string",
+            Val::List(_) => "list",
+            Val::Map(_) => "map",
+            Val::Func(_) => "function",
+        }
+    }
+    fn is_number(&self) -> bool {
+        matches!(self, Val::Int(_) | Val::Big(_) | Val::Float(_))
+    }
+    /// Truthiness used by conditions: `?-`, 0, "", ,[] and :[] are false
+    fn is_truthy(&self) -> bool {
+        match self {
+            Val::Int(i) => *i != 0,
+            Val::Big(_) => true,
+            Val::Float(f) => *f != 0.0,
+            Val::Bool(b) => *b,
+            Val::Str(s) => !s.is_empty(),
+            Val::List(v) => !v.is_empty(),
+            Val::Map(m) => !m.is_empty(),
+            Val::Func(_) => true,
+        }
+    }
+}
+
+fn run(code: &'static str) -> Result<(), String> {
+    let max_depth = match std::env::var("RIFF_MAX_DEPTH") {
+        Ok(n) => match n.trim().parse() {
+            Ok(n) if n > 0 => n,
+            _ => return Err(format!("RIFF_MAX_DEPTH must be a positive whole number, got '{}'", n)),
+        },
+        Err(_) => DEFAULT_MAX_DEPTH,
+    };
+    // the evaluator recurses for every call, body and expression, so the stack is sized from how deep
+    // the program's bodies nest; the parser recurses too, so that is measured on a thread of its own
+    let measure = std::thread::Builder::new()
+        .stack_size(STACK_BASE)
+        .spawn(move || program_levels(code))
+        .map_err(|e| format!("Cannot start the interpreter: {}", e))?;
+    let levels = match measure.join() {
+        Ok(levels) => levels,
+        Err(panic) => std::panic::resume_unwind(panic),
+    };
+    let max_nesting = max_depth.saturating_add(1 + MODULES.len()).saturating_mul(levels + 1);
+    let stack = STACK_PER_LEVEL.saturating_mul(max_nesting).saturating_add(STACK_BASE);
+    let worker = std::thread::Builder::new()
+        .stack_size(stack)
+        .spawn(move || run_program(code, max_depth, max_nesting))
+        .map_err(|e| format!("Cannot start the interpreter for a call depth of {}: {}", max_depth, e))?;
+    match worker.join() {
+        Ok(result) => result,
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
+}
+
+/// Most levels (see `Interp::enter`) any body of the program, its top level included, nests before
+/// the next call; a file that does not parse gives 0 and reports its error when the program runs
+fn program_levels(code: &str) -> usize {
+    let files = std::iter::once(code).chain(MODULES.iter().map(|&(_, module_code)| module_code));
+    let mut deepest = 0;
+    for (k, file_code) in files.enumerate() {
+        if let Ok(program) = parse_module(file_code, k) {
+            let top = body_levels(&program, &mut deepest);
+            deepest = deepest.max(top);
+        }
+    }
+    deepest
+}
+
+/// Levels of a body, the bodies of functions defined in it are measured separately into `deepest`
+fn body_levels(stmts: &[Stmt], deepest: &mut usize) -> usize {
+    let mut levels = 0;
+    for stmt in stmts {
+        let n = match stmt {
+            Stmt::Send { value, targets, .. } => {
+                let mut n = expr_levels(&value.expr, deepest);
+                for target in targets {
+                    if let Target::Index(chain, ..) = target {
+                        n = n.max(expr_levels(chain, deepest));
+                    }
+                }
+                n
+            }
+            Stmt::Call(call) => expr_levels(&call.expr, deepest),
+            Stmt::If(clauses) => {
+                let mut n = 0;
+                for (cond, body) in clauses {
+                    let cond = cond.as_ref().map_or(0, |c| expr_levels(&c.expr, deepest));
+                    n = n.max(cond).max(1 + body_levels(body, deepest));
+                }
+                n
+            }
+            Stmt::For { count: cond, body } | Stmt::While { cond, body } => {
+                expr_levels(&cond.expr, deepest).max(1 + body_levels(body, deepest))
+            }
+            Stmt::FuncDef(_, func) => {
+                let n = body_levels(&func.body, deepest);
+                *deepest = (*deepest).max(n);
+                0
+            }
+            Stmt::Import(_) | Stmt::Break | Stmt::Continue => 0,
+        };
+        levels = levels.max(n);
+    }
+    levels
+}
+
+fn expr_levels(e: &Expr, deepest: &mut usize) -> usize {
+    let inner = match e {
+        Expr::Lit(_) | Expr::Var(_) | Expr::Read(_) => 0,
+        Expr::CallOrIndex(_, items) | Expr::List(items) | Expr::Interp(items) | Expr::Macro(_, items) => {
+            items.iter().map(|item| expr_levels(item, deepest)).max().unwrap_or(0)
+        }
+        Expr::Index(list, args, _) => {
+            let n = args.iter().map(|arg| expr_levels(arg, deepest)).max().unwrap_or(0);
+            n.max(expr_levels(list, deepest))
+        }
+        Expr::Map(entries) => entries.iter().map(|(k, v)| expr_levels(k, deepest).max(expr_levels(v, deepest))).max().unwrap_or(0),
+        Expr::Lambda(func) => {
+            let n = body_levels(&func.body, deepest);
+            *deepest = (*deepest).max(n);
+            0
         }
+        Expr::Unary(_, operand) => expr_levels(operand, deepest),
+        Expr::Binary(_, a, b) => expr_levels(a, deepest).max(expr_levels(b, deepest)),
+    };
+    1 + inner
+}
+
+fn run_program(code: &str, max_depth: usize, max_nesting: usize) -> Result<(), String> {
+    let mut interp = Interp::default();
+    interp.max_depth = max_depth;
+    // enough for `max_depth` calls whatever their bodies, so only the call depth is ever reported
+    interp.max_nesting = max_nesting;
+    interp.modules.push(Rc::new(parse_module(code, 0)?));
+    // imported files are parsed up front, so a syntax error in any of them stops the program before it starts
+    for (k, &(name, module_code)) in MODULES.iter().enumerate() {
+        let program = parse_module(module_code, k + 1).map_err(|e| format!("{}: {}", name, e))?;
+        interp.modules.push(Rc::new(program));
     }
+    interp.imported = vec![false; interp.modules.len()];
+    interp.imported[0] = true;
+    // the executable's own arguments, without its path
+    let args = std::env::args().skip(1).map(Val::Str).collect();
+    interp.scopes.push(HashMap::new());
+    interp.scopes[0].insert("args".to_string(), Val::List(args));
+    let program = interp.modules[0].clone();
+    let result = interp.exec_block(&program).and_then(top_level);
+    // errors inside functions end with the calls that led to them
+    result.map_err(|e| match interp.trace.take() {
+        Some(calls) if !calls.is_empty() => format!("{}\n{}", e, format_trace(&calls)),
+        _ => e,
+    })
 }
 
-fn run(code: &str) -> Result<(), String> {
-    let mut vars: HashMap<String, Val> = HashMap::new();
-    run_block_simple_loop(code, &mut vars)
+/// Call stack for an error, most recent call first. Repeats of the same call are shown once
+/// and a long stack only keeps its most recent and outermost calls.
+fn format_trace(calls: &[Frame]) -> String {
+    let mut groups: Vec<(&Frame, usize)> = Vec::new();
+    for frame in calls.iter().rev() {
+        match groups.last_mut() {
+            Some((last, n)) if *last == frame => *n += 1,
+            _ => groups.push((frame, 1)),
+        }
+    }
+    let line = |&(frame, n): &(&Frame, usize)| {
+        let place = match frame.module {
+            0 => format!("line {}", frame.line),
+            k => format!("{} line {}", MODULES[k - 1].0, frame.line),
+        };
+        let repeats = if n > 1 { format!(" ({} times)", n) } else { String::new() };
+        format!("  {}, called at {}{}", frame.name, place, repeats)
+    };
+    let mut lines = vec!["Call stack (most recent call first):".to_string()];
+    if groups.len() <= TRACE_RECENT + TRACE_OUTER + 1 {
+        lines.extend(groups.iter().map(line));
+    } else {
+        let hidden = &groups[TRACE_RECENT..groups.len() - TRACE_OUTER];
+        lines.extend(groups[..TRACE_RECENT].iter().map(line));
+        lines.push(format!("  ... {} more calls", hidden.iter().map(|g| g.1).sum::<usize>()));
+        lines.extend(groups[groups.len() - TRACE_OUTER..].iter().map(line));
+    }
+    lines.join("\n")
 }
 
-fn run_block_simple_loop(code: &str, vars: &mut HashMap<String, Val>) -> Result<(), String> {
-    let mut i = 0usize;
+fn parse_module(code: &str, module: usize) -> Result<Vec<Stmt>, String> {
+    let tokens = lex(code)?;
+    let mut parser = Parser::new(code, tokens);
+    parser.module = module;
+    parser.parse_program()
+}
+
+/// How the top level of a file may finish
+fn top_level(flow: Flow) -> Result<(), String> {
+    match flow {
+        Flow::Normal => Ok(()),
+        Flow::Break => Err("'|' (break) used outside of a loop".into()),
+        Flow::Continue => Err("'!|' (continue) used outside of a loop".into()),
+        Flow::Return(_) => Err("'&' (return) used outside of a function".into()),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Lexer: source text to tokens with spans
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokKind {
+    Num(i64),
+    Big(BigInt),
+    Float(f64),
+    Str(String),
+    /// a string literal containing `{expr}`
+    Interp(Vec<StrPart>),
+    Bool(bool),
+    Ident(String),
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Caret,
+    Eq,
+    Lt,
+    Le,
+    Ge,
+    /// `>`: the send operator in statements, greater than inside conditions
+    Gt,
+    /// `>>`: always greater than
+    GtGt,
+    /// augmented send like `+>` or `^>`
+    AugSend(BinOp),
+    AndAnd,
+    OrOr,
+    Amp,
+    Pipe,
+    Bang,
+    Question,
+    Dollar,
+    Dot,
+    DotDot,
+    DotHash,
+    /// `#"path"`: import
+    Hash,
+    Comma,
+    Colon,
+    Tilde,
+    Semi,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    LBrace,
+    RBrace,
+}
+
+/// A piece of a string literal: plain text or the source of an embedded `{expr}`
+#[derive(Debug, Clone, PartialEq)]
+enum StrPart {
+    Text(String),
+    Code(String),
+}
+
+/// Byte range of a token plus its 1-based line and column
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    start: usize,
+    end: usize,
+    line: usize,
+    col: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokKind,
+    span: Span,
+}
+
+/// Position of the '}' closing the `{expr}` whose code starts at `i`, braces of nested blocks
+/// and whole string literals inside the code are skipped: "{m["a"]}"
+fn embedded_end(bytes: &[u8], mut i: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => i = string_end(bytes, i + 1)?,
+            b'{' => { depth += 1; i += 1; }
+            b'}' if depth == 0 => return Some(i),
+            b'}' => { depth -= 1; i += 1; }
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// Position just after the '"' ending a string literal whose text starts at `i`
+fn string_end(bytes: &[u8], mut i: usize) -> Option<usize> {
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'"' => return Some(i + 1),
+            b'{' => i = embedded_end(bytes, i + 1)? + 1,
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+fn lex(code: &str) -> Result<Vec<Token>, String> {
     let bytes = code.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0usize;
+    let mut line = 1usize;
+    let mut line_start = 0usize;
     while i < bytes.len() {
-        // skip whitespace
-        while i < bytes.len() && (bytes[i] as char).is_whitespace() { i += 1; }
-        if i >= bytes.len() { break; }
         let c = bytes[i] as char;
-        if c == '@' { // comment
-            while i < bytes.len() && (bytes[i] as char) != '\n' { i += 1; }
+        let next = bytes.get(i + 1).map(|&b| b as char);
+        if c == '\n' {
+            i += 1;
+            line += 1;
+            line_start = i;
             continue;
-        } else if c == '?' || (c == '!' && i + 1 < bytes.len() && ((bytes[i+1] as char)=='?' || (bytes[i+1] as char)=='!')) {
-            // if / else-if / else chain
-            handle_if_chain(code, &mut i, vars)?;
+        }
+        if c.is_whitespace() { i += 1; continue; }
+        let start = i;
+        let col = i - line_start + 1;
+        let err_at = move |msg: String| format!("line {}, column {}: {}", line, col, msg);
+
+        // comments: `@` to the end of the line, `<@ ... @>` (nested pairs are allowed)
+        if c == '<' && next == Some('@') {
+            let mut depth = 0usize;
+            while i < bytes.len() {
+                let cc = bytes[i] as char;
+                let nc = bytes.get(i + 1).map(|&b| b as char);
+                if cc == '<' && nc == Some('@') {
+                    depth += 1;
+                    i += 2;
+                } else if cc == '@' && nc == Some('>') {
+                    depth -= 1;
+                    i += 2;
+                    if depth == 0 { break; }
+                } else {
+                    if cc == '\n' { line += 1; line_start = i + 1; }
+                    i += 1;
+                }
+            }
+            if depth > 0 { return Err(err_at("unterminated '<@', multiline comment never closed".into())); }
             continue;
-        } else if c == '"' {
-            // string literal then expect >
-            let (lit, ni) = extract_string(code, i)?;
-            i = ni;
-            skip_ws_bytes(code.as_bytes(), &mut i);
-            if i < bytes.len() && (bytes[i] as char) == '>' { i += 1; skip_ws_bytes(code.as_bytes(), &mut i); if i < bytes.len() && (bytes[i] as char) == '.' { println!("{}", lit); i += 1; } else { let (targets, ni2) = extract_targets(code, i)?; i = ni2; for t in targets { vars.insert(t, Val::Str(lit.clone())); } } }
-            if i < bytes.len() && (bytes[i] as char) == ';' { i += 1; }
+        }
+        if c == '@' {
+            while i < bytes.len() && bytes[i] != b'\n' { i += 1; }
             continue;
-        } else if c == '*' {
-            // loop: *N{...} or while: *?condition{...}
-            i += 1;
-            skip_ws_bytes(code.as_bytes(), &mut i);
-            
-            // check if it's a while loop (*? condition)
-            if i < bytes.len() && (bytes[i] as char) == '?' {
-                // while loop
+        }
+
+        let kind = if c.is_ascii_digit() {
+            // support integer, decimal, and scientific notation (e.g., 1e6, 2.5e3)
+            // a decimal point or a negative exponent makes a float, 45e5 is still an integer
+            let is_digit = |j: usize| bytes.get(j).map_or(false, |b| b.is_ascii_digit());
+            while is_digit(i) { i += 1; }
+            let mut is_float = false;
+            if bytes.get(i) == Some(&b'.') && is_digit(i + 1) {
+                is_float = true;
                 i += 1;
-                skip_ws_bytes(code.as_bytes(), &mut i);
-                // read until '{' and this is the condition
-                let start_expr = i;
-                while i < bytes.len() && (bytes[i] as char) != '{' { i += 1; }
-                let cond_str = code[start_expr..i].trim();
-                skip_ws_bytes(code.as_bytes(), &mut i);
-                if i >= bytes.len() || (bytes[i] as char) != '{' { return Err("Expected '{' after while condition".into()); }
-                let (block, ni2) = extract_braced_block(code, i)?;
-                i = ni2;
-                
-                // while loop: keep executing block while condition is true
-                let mut idx = 0;
-                loop {
-                    // keep _ as working
-                    vars.insert("_".to_string(), Val::Int(idx as i64));
-                    let cond_val = eval_expr(cond_str, vars)?;
-                    if cond_val.as_i64() == 0 {
-                        break;
-                    }
-                    run_block_simple_loop(&block, vars)?;
-                    idx += 1;
-                }
-                continue;
-            } else {
-                // regular for loop: *N{...}
-                // read until '{' and evaluate the expression
-                let start_expr = i;
-                while i < bytes.len() && (bytes[i] as char) != '{' { i += 1; }
-                let expr_str = code[start_expr..i].trim();
-                let num_val = eval_expr(expr_str, vars)?;
-                let num = num_val.as_i64() as usize;
-                skip_ws_bytes(code.as_bytes(), &mut i);
-                if i >= bytes.len() || (bytes[i] as char) != '{' { return Err("Expected '{' after loop count".into()); }
-                let (block, ni2) = extract_braced_block(code, i)?;
-                i = ni2;
-                for idx in 0..num {
-                    vars.insert("_".to_string(), Val::Int(idx as i64));
-                    run_block_simple_loop(&block, vars)?;
+                while is_digit(i) { i += 1; }
+            }
+            if matches!(bytes.get(i), Some(b'e') | Some(b'E')) {
+                // allow optional sign after exponent
+                let sign = matches!(bytes.get(i + 1), Some(b'+') | Some(b'-')) as usize;
+                if is_digit(i + 1 + sign) {
+                    is_float |= bytes[i + 1] == b'-';
+                    i += 1 + sign;
+                    while is_digit(i) { i += 1; }
                 }
-                continue;
             }
-        } else {
-            // read until semicolon
-            let start = i;
-            while i < bytes.len() && (bytes[i] as char) != ';' { i += 1; }
-            let stmt = &code[start..i];
-            if i < bytes.len() && (bytes[i] as char) == ';' { i += 1; }
-            if stmt.trim().is_empty() { continue; }
-            exec_stmt(stmt, vars)?;
-            continue;
-        }
-    }
-    Ok(())
-}
-
-fn exec_stmt(stmt: &str, vars: &mut HashMap<String, Val>) -> Result<(), String> {
-    let s = stmt.trim();
-    if s.is_empty() { return Ok(()); }
-    // find '>' (the core send operator)
-    if let Some(pos) = s.find('>') {
-        if pos == 0 { return Err(format!("Invalid statement: {}", s)); }
-        let left = s[..pos].trim();
-        // check if there is an augment operator immediately before '>' like +> or ^>
-        let op_char = left.chars().last();
-        let (expr_str, op) = if let Some(c) = op_char {
-            if "+-*/^%".contains(c) {
-                // augmented: expr then operator char is last char of left
-                let mut left_chars = left.chars().collect::<Vec<_>>();
-                left_chars.pop();
-                (left_chars.into_iter().collect::<String>().trim().to_string(), Some(c))
-            } else { (left.to_string(), None) }
-        } else { (left.to_string(), None) };
-
-        let right = s[pos + 1..].trim();
-        // evaluate expression
-        let val = eval_expr(expr_str.as_str(), vars)?;
-
-        if right == "." {
-            // print
-            println!("{}", val.as_string());
-            return Ok(());
-        }
-
-        // right side may be comma-separated targets
-        let targets: Vec<&str> = right.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()).collect();
-        for t in targets {
-            // target should be a variable name (letters)
-            if t.is_empty() { continue; }
-            if op.is_none() {
-                // assignment
-                vars.insert(t.to_string(), val.clone());
+            let num_str = &code[start..i];
+            if is_float {
+                let f: f64 = num_str.parse().map_err(|e| err_at(format!("Failed to parse float: {}", e)))?;
+                TokKind::Float(f)
             } else {
-                // augmented: variable = variable (op) value
-                let opch = op.unwrap();
-                let cur = vars.get(t).cloned().unwrap_or(Val::Int(0));
-                let newv = match (cur, val.clone(), opch) {
-                    (Val::Int(a), Val::Int(b), '+') => Val::Int(a + b),
-                    (Val::Int(a), Val::Int(b), '-') => Val::Int(a - b),
-                    (Val::Int(a), Val::Int(b), '*') => Val::Int(a * b),
-                    (Val::Int(a), Val::Int(b), '/') => Val::Int(a / b),
-                    (Val::Int(a), Val::Int(b), '%') => Val::Int(a % b),
-                    (Val::Int(a), Val::Int(b), '^') => Val::Int(a.pow(b as u32)),
-                    // append int to list
-                    (Val::List(mut vec), Val::Int(b), '+') => {
-                        vec.push(Val::Int(b));
-                        Val::List(vec)
-                    }
-                    // fallback: try numeric
-                    (Val::Str(sa), Val::Int(b), '+') => Val::Str(format!("{}{}", sa, b)),
-                    _ => return Err(format!("Unsupported augmented op on types")),
+                // an integer exponent just appends zeros: 45e5 is 4500000
+                let (mantissa, zeros) = match num_str.find(|ch| ch == 'e' || ch == 'E') {
+                    Some(e) => (&num_str[..e], num_str[e + 1..].trim_start_matches('+').parse().unwrap_or(usize::MAX)),
+                    None => (num_str, 0),
                 };
-                vars.insert(t.to_string(), newv);
+                if zeros as f64 > MAX_POW_DIGITS {
+                    return Err(err_at(format!("Overflow: number literal '{}' has too many digits", num_str)));
+                }
+                let digits = format!("{}{}", mantissa, "0".repeat(zeros));
+                match digits.parse() {
+                    Ok(n) => TokKind::Num(n),
+                    // too large for an i64
+                    Err(_) => TokKind::Big(BigInt::parse(&digits)),
+                }
             }
-        }
-        Ok(())
-    } else {
-        Err(format!("No '>' operator found in statement: {}", s))
-    }
-}
-
-fn eval_expr(s: &str, vars: &HashMap<String, Val>) -> Result<Val, String> {
-    let expr = s.trim();
-    if expr.is_empty() { return Ok(Val::Int(0)); }
-    // macros: $a[b]
-    if expr.starts_with('$') {
-        // parse $name[arg]
-        if let Some(br) = expr.find('[') {
-            let name = &expr[1..br];
-            if let Some(end) = expr.find(']') {
-                let arg = &expr[br+1..end].trim();
-                let val = vars.get(*arg).cloned().unwrap_or(Val::Int(0));
-                match name {
-                    "s" => {
-                        // sum macro: sum the elements of a list
-                        match val {
-                            Val::List(items) => {
-                                let sum: i64 = items.iter().map(|v| v.as_i64()).sum();
-                                return Ok(Val::Int(sum));
-                            }
-                            Val::Int(n) => return Ok(Val::Int(n)),
-                            Val::Str(st) => {
-                                if let Ok(n) = st.parse::<i64>() {
-                                    return Ok(Val::Int(n));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') { i += 1; }
+            TokKind::Ident(code[start..i].to_string())
+        } else if c == '"' {
+            // literal text and `{expr}` pieces, escapes are resolved here
+            let mut parts = Vec::new();
+            let mut text = String::new();
+            i += 1;
+            loop {
+                let ch = match code[i..].chars().next() {
+                    Some(ch) => ch,
+                    None => return Err(err_at("unterminated string".into())),
+                };
+                i += ch.len_utf8();
+                match ch {
+                    '"' => break,
+                    '\\' => {
+                        let esc = code[i..].chars().next().ok_or_else(|| err_at("unterminated string".into()))?;
+                        i += esc.len_utf8();
+                        text.push(match esc {
+                            '"' | '\\' | '{' | '}' => esc,
+                            'n' => '\n',
+                            't' => '\t',
+                            'u' => {
+                                let close = code[i..].find('}').filter(|_| code[i..].starts_with('{'));
+                                let hex = close.map(|c| &code[i + 1..i + c]).unwrap_or("");
+                                let decoded = u32::from_str_radix(hex, 16).ok().and_then(std::char::from_u32);
+                                match (close, decoded) {
+                                    (Some(c), Some(d)) => { i += c + 1; d }
+                                    _ => return Err(err_at("invalid unicode escape, expected \\u{hex}".into())),
                                 }
-                                return Err(format!("Cannot sum string '{}': not a valid number", st));
                             }
-                        }
+                            _ => return Err(err_at(format!("unknown escape '\\{}' in string", esc))),
+                        });
                     }
-                    "l" => {
-                        // length macro: length of string or list
-                        match val {
-                            Val::List(items) => {
-                                return Ok(Val::Int(items.len() as i64));
-                            }
-                            Val::Str(st) => {
-                                return Ok(Val::Int(st.chars().count() as i64));
-                            }
-                            Val::Int(n) => {
-                                return Err(format!("Cannot get length of integer '{}'", n));
+                    '{' => match embedded_end(bytes, i) {
+                        Some(end) => {
+                            parts.push(StrPart::Text(std::mem::replace(&mut text, String::new())));
+                            parts.push(StrPart::Code(code[i..end].to_string()));
+                            for (n, _) in code[i..end].match_indices('\n') {
+                                line += 1;
+                                line_start = i + n + 1;
                             }
+                            i = end + 1;
                         }
+                        None => return Err(err_at("unclosed '{' in string, use \\{ for a literal brace".into())),
+                    },
+                    '\n' => {
+                        line += 1;
+                        line_start = i;
+                        text.push(ch);
                     }
-                    _ => return Err(format!("Unknown macro: ${} (line with expression: {})", name, expr)),
+                    _ => text.push(ch),
                 }
+            }
+            if parts.is_empty() {
+                TokKind::Str(text)
             } else {
-                return Err(format!("Macro ${}[...] missing closing bracket ']'", name));
+                parts.push(StrPart::Text(text));
+                TokKind::Interp(parts)
             }
         } else {
-            return Err(format!("Macro expression '{}' missing opening bracket '['", expr));
-        }
-    }
-    // list literal: ,[a,b,c]
-    if expr.starts_with(',') {
-        // expect ,[ ... ]
-        let rest = expr[1..].trim();
-        if rest.starts_with('[') && rest.ends_with(']') {
-            let inner = &rest[1..rest.len()-1];
-            if inner.trim().is_empty() {
-                return Ok(Val::List(Vec::new()));
-            }
-            let mut items = Vec::new();
-            for part in inner.split(',') {
-                let p = part.trim();
-                if p.is_empty() { continue; }
-                // try parse number or string
-                if p.starts_with('"') && p.ends_with('"') && p.len()>=2 {
-                    items.push(Val::Str(p[1..p.len()-1].to_string()));
-                } else {
-                    let n: i64 = p.parse().map_err(|_| format!("Invalid list element '{}': expected integer or quoted string", p))?;
-                    items.push(Val::Int(n));
+            // augmented send: an arithmetic operator followed by '>' (spaces allowed in between)
+            let aug = match c {
+                '+' => Some(BinOp::Add),
+                '-' => Some(BinOp::Sub),
+                '*' => Some(BinOp::Mul),
+                '/' => Some(BinOp::Div),
+                '^' => Some(BinOp::Pow),
+                '%' => Some(BinOp::Mod),
+                _ => None,
+            };
+            let mut j = i + 1;
+            while j < bytes.len() && (bytes[j] == b' ' || bytes[j] == b'\t') { j += 1; }
+            let sends = bytes.get(j) == Some(&b'>') && !matches!(bytes.get(j + 1), Some(b'=') | Some(b'>'));
+            match aug {
+                Some(op) if sends => { i = j + 1; TokKind::AugSend(op) }
+                _ => {
+                    // `?-x = 3 {` is an if on -x, so `?+`/`?-` directly followed by an operand are not booleans
+                    let operand_after = bytes.get(i + 2).map_or(false, |&b| b.is_ascii_alphanumeric() || b"_($\"".contains(&b));
+                    let (kind, len) = match (c, next) {
+                        ('?', Some('+')) if !operand_after => (TokKind::Bool(true), 2),
+                        ('?', Some('-')) if !operand_after => (TokKind::Bool(false), 2),
+                        ('&', Some('&')) => (TokKind::AndAnd, 2),
+                        ('.', Some('.')) => (TokKind::DotDot, 2),
+                        ('.', Some('#')) => (TokKind::DotHash, 2),
+                        ('|', Some('|')) => (TokKind::OrOr, 2),
+                        ('<', Some('=')) => (TokKind::Le, 2),
+                        ('>', Some('=')) => (TokKind::Ge, 2),
+                        ('<', Some('<')) => (TokKind::Lt, 2),
+                        ('>', Some('>')) => (TokKind::GtGt, 2),
+                        ('+', _) => (TokKind::Plus, 1),
+                        ('-', _) => (TokKind::Minus, 1),
+                        ('*', _) => (TokKind::Star, 1),
+                        ('/', _) => (TokKind::Slash, 1),
+                        ('%', _) => (TokKind::Percent, 1),
+                        ('^', _) => (TokKind::Caret, 1),
+                        ('=', _) => (TokKind::Eq, 1),
+                        ('<', _) => (TokKind::Lt, 1),
+                        ('>', _) => (TokKind::Gt, 1),
+                        ('&', _) => (TokKind::Amp, 1),
+                        ('|', _) => (TokKind::Pipe, 1),
+                        ('!', _) => (TokKind::Bang, 1),
+                        ('?', _) => (TokKind::Question, 1),
+                        ('$', _) => (TokKind::Dollar, 1),
+                        ('#', _) => (TokKind::Hash, 1),
+                        ('.', _) => (TokKind::Dot, 1),
+                        (',', _) => (TokKind::Comma, 1),
+                        (':', _) => (TokKind::Colon, 1),
+                        ('~', _) => (TokKind::Tilde, 1),
+                        (';', _) => (TokKind::Semi, 1),
+                        ('(', _) => (TokKind::LParen, 1),
+                        (')', _) => (TokKind::RParen, 1),
+                        ('[', _) => (TokKind::LBracket, 1),
+                        (']', _) => (TokKind::RBracket, 1),
+                        ('{', _) => (TokKind::LBrace, 1),
+                        ('}', _) => (TokKind::RBrace, 1),
+                        _ => {
+                            let ch = code[i..].chars().next().unwrap_or(c);
+                            return Err(err_at(format!("Unexpected character '{}'", ch)));
+                        }
+                    };
+                    i += len;
+                    kind
                 }
             }
-            return Ok(Val::List(items));
-        } else {
-            return Err(format!("Invalid list literal '{}': expected format: ,[ item, item, ... ]", expr));
-        }
+        };
+        out.push(Token { kind, span: Span { start, end: i, line, col } });
     }
-    // if expression is a string literal (rare here)
-    if expr.starts_with('"') && expr.ends_with('"') && expr.len() >= 2 {
-        let inner = &expr[1..expr.len()-1];
-        return Ok(Val::Str(inner.to_string()));
+    Ok(out)
+}
+
+// ---------------------------------------------------------------------------
+// AST
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinOp { Add, Sub, Mul, Div, Mod, Pow, Eq, Lt, Gt, Le, Ge, In, And, Or }
+
+impl BinOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+            BinOp::Pow => "^",
+            BinOp::Eq => "=",
+            BinOp::Lt => "<",
+            BinOp::Gt => ">",
+            BinOp::Le => "<=",
+            BinOp::Ge => ">=",
+            BinOp::In => "~",
+            BinOp::And => "&&",
+            BinOp::Or => "||",
+        }
     }
-    // Evaluate using a simple shunting-yard to RPN for integers
-    // Tokenize with variable handling (variables and list indexing are resolved in tokenizer)
-    let tokens = tokenize(expr, vars).map_err(|e| format!("In expression '{}': {}", expr, e))?;
-    let rpn = to_rpn(tokens).map_err(|e| format!("In expression '{}': {}", expr, e))?;
-    let v = eval_rpn(rpn).map_err(|e| format!("In expression '{}': {}", expr, e))?;
-    Ok(Val::Int(v))
 }
 
-// Tokenizer
-#[derive(Debug, Clone)]
-enum Tok { Num(i64), Op(String) }
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UnOp { Neg, Not }
 
-fn tokenize(s: &str, vars: &HashMap<String, Val>) -> Result<Vec<Tok>, String> {
-    let mut i = 0usize;
-    let bytes = s.as_bytes();
-    let mut out = Vec::new();
-    while i < bytes.len() {
-        let c = bytes[i] as char;
-        if c.is_whitespace() { i += 1; continue; }
-        // numbers (with optional decimal and exponent)
-        if c.is_ascii_digit() {
-            // support integer, decimal, and scientific notation (e.g., 1e6, 2.5e3)
-            let start = i;
-            let mut seen_e = false;
-            let mut seen_dot = false;
-            while i < bytes.len() {
-                let ch = bytes[i] as char;
-                if ch.is_ascii_digit() {
-                    i += 1; continue;
-                }
-                if (ch == 'e' || ch == 'E') && !seen_e {
-                    seen_e = true;
-                    i += 1;
-                    // allow optional sign after exponent
-                    if i < bytes.len() {
-                        let nc = bytes[i] as char;
-                        if nc == '+' || nc == '-' { i += 1; }
-                    }
-                    continue;
-                }
-                if ch == '.' && !seen_dot && !seen_e {
-                    seen_dot = true;
-                    i += 1; continue;
-                }
-                break;
+/// What a console read takes from stdin
+#[derive(Debug, Clone, Copy)]
+enum ReadKind {
+    /// `.`: one line without its newline
+    Line,
+    /// `..`: everything up to end of input
+    All,
+    /// `.#`: one line parsed as an integer
+    Int,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Lit(Val),
+    Var(String),
+    /// `name[args]`: a call when `name` is a function, list indexing otherwise
+    CallOrIndex(String, Vec<Expr>),
+    /// `list[index]` after another index or call, with the source text of `list` for errors
+    /// (`f[0][a, b]` calls the function in f[0], so there may be several arguments)
+    Index(Box<Expr>, Vec<Expr>, Rc<str>),
+    /// `,[a, b]`
+    List(Vec<Expr>),
+    /// `:[k: v]`
+    Map(Vec<(Expr, Expr)>),
+    /// `.`, `..` or `.#` on the left of `>`: read from stdin
+    Read(ReadKind),
+    /// `"text {expr} text"`: the pieces are joined as strings
+    Interp(Vec<Expr>),
+    /// `$name[args]`
+    Macro(String, Vec<Expr>),
+    /// `$[a, b]{body}`: an anonymous function
+    Lambda(Rc<Func>),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+/// A top-level expression of a statement together with its source text for error messages
+#[derive(Debug)]
+struct SrcExpr {
+    expr: Expr,
+    src: Rc<str>,
+    line: usize,
+}
+
+#[derive(Debug)]
+enum Target {
+    Console,
+    Return,
+    /// `code > !`: stop the program with an exit status
+    Exit,
+    /// a variable, `:name` (the bool) always writes the global one
+    Var(String, bool),
+    /// `name[i]`, `name[i][j]`: an `Expr::Index` chain ending in `Expr::Var`, with its source text
+    /// (`:name[i]` writes into the global list)
+    Index(Expr, Rc<str>, bool),
+}
+
+#[derive(Debug)]
+enum Stmt {
+    /// `expr > targets` or `expr op> targets`
+    Send { value: SrcExpr, aug: Option<BinOp>, targets: Vec<Target> },
+    /// a bare `name[args]` call whose result is discarded
+    Call(SrcExpr),
+    /// `?a{}` followed by any `!?b{}` and an optional `!!{}` (the clause without a condition)
+    If(Vec<(Option<SrcExpr>, Vec<Stmt>)>),
+    For { count: SrcExpr, body: Vec<Stmt> },
+    While { cond: SrcExpr, body: Vec<Stmt> },
+    FuncDef(String, Rc<Func>),
+    /// `#"path"`: runs another file the first time it is imported, holds the file's number
+    Import(usize),
+    Break,
+    Continue,
+}
+
+/// A user-defined function: `$name[a,b]{body}`, or `$[a,b]{body}` without a name
+#[derive(Debug)]
+struct Func {
+    name: Option<String>,
+    params: Vec<String>,
+    body: Vec<Stmt>,
+    /// file the function was defined in, for errors
+    module: usize,
+}
+
+// ---------------------------------------------------------------------------
+// Parser: recursive descent from tokens to the AST
+// ---------------------------------------------------------------------------
+
+struct Parser<'a> {
+    code: &'a str,
+    toks: Vec<Token>,
+    pos: usize,
+    /// whether a bare `>` ends the expression (left side of a statement) or compares
+    gt_is_send: bool,
+    /// file being parsed, imports are looked up relative to it
+    module: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(code: &'a str, toks: Vec<Token>) -> Self {
+        Parser { code, toks, pos: 0, gt_is_send: false, module: 0 }
+    }
+
+    fn peek(&self) -> Option<&TokKind> { self.toks.get(self.pos).map(|t| &t.kind) }
+    fn peek_at(&self, n: usize) -> Option<&TokKind> { self.toks.get(self.pos + n).map(|t| &t.kind) }
+    fn at(&self, kind: &TokKind) -> bool { self.peek() == Some(kind) }
+
+    fn eat(&mut self, kind: &TokKind) -> bool {
+        if self.at(kind) { self.pos += 1; true } else { false }
+    }
+
+    fn err<T>(&self, msg: &str) -> Result<T, String> {
+        match self.toks.get(self.pos) {
+            Some(t) => Err(format!("line {}, column {}: {}", t.span.line, t.span.col, msg)),
+            None => Err(format!("end of input: {}", msg)),
+        }
+    }
+
+    fn expect(&mut self, kind: TokKind, msg: &str) -> Result<(), String> {
+        if self.eat(&kind) { Ok(()) } else { self.err(msg) }
+    }
+
+    /// Line of token `pos`, 0 past the end
+    fn line_at(&self, pos: usize) -> usize {
+        self.toks.get(pos).map_or(0, |t| t.span.line)
+    }
+
+    /// Source text from token `from` up to (excluding) the current token
+    fn text_from(&self, from: usize) -> Rc<str> {
+        if from >= self.pos { return Rc::from(""); }
+        let start = self.toks[from].span.start;
+        let end = self.toks[self.pos - 1].span.end;
+        Rc::from(self.code[start..end].trim())
+    }
+
+    fn parse_program(&mut self) -> Result<Vec<Stmt>, String> {
+        let stmts = self.parse_stmts()?;
+        if self.pos < self.toks.len() {
+            return self.err("unmatched '}'");
+        }
+        Ok(stmts)
+    }
+
+    /// Statements until the end of input or a closing '}'
+    fn parse_stmts(&mut self) -> Result<Vec<Stmt>, String> {
+        let mut stmts = Vec::new();
+        while let Some(kind) = self.peek() {
+            match kind {
+                TokKind::RBrace => break,
+                TokKind::Semi => self.pos += 1,
+                _ => stmts.push(self.parse_stmt()?),
             }
-            let num_str = &s[start..i];
-            // parse as float if it contains '.' or 'e'/'E', otherwise parse as integer
-            let num: i64 = if num_str.contains('.') || num_str.contains('e') || num_str.contains('E') {
-                let f: f64 = num_str.parse().map_err(|e| format!("Failed to parse float: {}", e))?;
-                f as i64
-            } else {
-                num_str.parse().map_err(|e| format!("Failed to parse number: {}", e))?
-            };
-            out.push(Tok::Num(num));
-            continue;
         }
-        // variables and identifiers: letters, possibly followed by alphanumeric/_ and optional [index]
-        if c.is_alphabetic() || c == '_' {
-            let start = i;
-            i += 1;
-            while i < bytes.len() {
-                let ch = bytes[i] as char;
-                if ch.is_alphanumeric() || ch == '_' { i += 1; } else { break; }
-            }
-            let name = &s[start..i];
-            // handle optional indexing like name[<number>]
-            if i < bytes.len() && (bytes[i] as char) == '[' {
-                i += 1; // consume '['
-                let idx_start = i;
-                while i < bytes.len() && (bytes[i] as char) != ']' { i += 1; }
-                if i >= bytes.len() { return Err(format!("Unclosed '[' in variable indexing for '{}'", name)); }
-                let idx_str = &s[idx_start..i];
-                i += 1; // consume ']'
-                let index: i64 = idx_str.trim().parse().unwrap_or(0);
-                if let Some(Val::List(items)) = vars.get(name) {
-                    let idx = if index < 0 { (items.len() as i64 + index) as usize } else { index as usize };
-                    if idx < items.len() { out.push(Tok::Num(items[idx].as_i64())); } else { out.push(Tok::Num(0)); }
-                } else { out.push(Tok::Num(0)); }
-            } else {
-                // plain variable
-                out.push(Tok::Num(vars.get(name).map(|v| v.as_i64()).unwrap_or(0)));
+        Ok(stmts)
+    }
+
+    fn parse_block(&mut self, after: &str) -> Result<Vec<Stmt>, String> {
+        self.expect(TokKind::LBrace, &format!("Expected '{{' after {}", after))?;
+        let stmts = self.parse_stmts()?;
+        self.expect(TokKind::RBrace, "Expected '}' to close block")?;
+        Ok(stmts)
+    }
+
+    fn parse_stmt(&mut self) -> Result<Stmt, String> {
+        match (self.peek(), self.peek_at(1)) {
+            (Some(TokKind::Question), _) => self.parse_if(),
+            (Some(TokKind::Bang), Some(TokKind::Question)) | (Some(TokKind::Bang), Some(TokKind::Bang)) => {
+                self.err("'!?' or '!!' without a preceding '?' clause")
             }
-            continue;
+            // break: | or continue: !| (the rest of the block is skipped)
+            (Some(TokKind::Pipe), _) => { self.pos += 1; Ok(Stmt::Break) }
+            (Some(TokKind::Bang), Some(TokKind::Pipe)) => { self.pos += 2; Ok(Stmt::Continue) }
+            (Some(TokKind::Star), _) => self.parse_loop(),
+            (Some(TokKind::Dollar), _) if self.is_func_def() => self.parse_func_def(),
+            (Some(TokKind::Hash), _) => self.parse_import(),
+            _ => self.parse_send(),
         }
-        // multi-char operators: ||, &&, <=, >=
-        if i + 1 < bytes.len() && c == '|' && (bytes[i + 1] as char) == '|' {
-            out.push(Tok::Op("||".to_string()));
-            i += 2;
-            continue;
+    }
+
+    fn parse_if(&mut self) -> Result<Stmt, String> {
+        self.expect(TokKind::Question, "Expected '?'")?;
+        let cond = self.parse_cond()?;
+        let mut clauses = vec![(Some(cond), self.parse_block("if condition")?)];
+        loop {
+            match (self.peek(), self.peek_at(1)) {
+                (Some(TokKind::Bang), Some(TokKind::Question)) => {
+                    self.pos += 2;
+                    let cond = self.parse_cond()?;
+                    clauses.push((Some(cond), self.parse_block("if condition")?));
+                }
+                (Some(TokKind::Bang), Some(TokKind::Bang)) => {
+                    self.pos += 2;
+                    clauses.push((None, self.parse_block("'!!'")?));
+                    break;
+                }
+                _ => break,
+            }
         }
-        if i + 1 < bytes.len() && c == '&' && (bytes[i + 1] as char) == '&' {
-            out.push(Tok::Op("&&".to_string()));
-            i += 2;
-            continue;
+        Ok(Stmt::If(clauses))
+    }
+
+    /// `*N{...}` or the while loop `*?condition{...}`
+    fn parse_loop(&mut self) -> Result<Stmt, String> {
+        self.expect(TokKind::Star, "Expected '*'")?;
+        if self.eat(&TokKind::Question) {
+            let cond = self.parse_cond()?;
+            let body = self.parse_block("while condition")?;
+            Ok(Stmt::While { cond, body })
+        } else {
+            let count = self.parse_cond()?;
+            let body = self.parse_block("loop count")?;
+            Ok(Stmt::For { count, body })
         }
-        if i + 1 < bytes.len() && c == '<' && (bytes[i + 1] as char) == '=' {
-            out.push(Tok::Op("<=".to_string()));
-            i += 2;
-            continue;
+    }
+
+    /// `$name[...]` directly followed by `{` is a definition, anything else is a macro
+    fn is_func_def(&self) -> bool {
+        if !matches!(self.peek_at(1), Some(TokKind::Ident(_))) || self.peek_at(2) != Some(&TokKind::LBracket) {
+            return false;
         }
-        if i + 1 < bytes.len() && c == '>' && (bytes[i + 1] as char) == '=' {
-            out.push(Tok::Op(">=".to_string()));
-            i += 2;
-            continue;
+        let mut depth = 0usize;
+        for (n, t) in self.toks[self.pos + 2..].iter().enumerate() {
+            match t.kind {
+                TokKind::LBracket => depth += 1,
+                TokKind::RBracket => {
+                    depth -= 1;
+                    if depth == 0 { return self.peek_at(n + 3) == Some(&TokKind::LBrace); }
+                }
+                _ => {}
+            }
         }
-        // single-char operators and parentheses: < > = etc
-        if "+-*/^()%<>=".contains(c) { out.push(Tok::Op(c.to_string())); i += 1; continue; }
-        return Err(format!("Unexpected character '{}' in expression at position {}", c, i));
+        false
     }
-    Ok(out)
-}
 
-fn prec(op: &str) -> i32 { 
-    match op { 
-        "^" => 4, 
-        "*" | "/" | "%" => 3, 
-        "+" | "-" => 2, 
-        "=" | "<" | ">" | "<=" | ">=" => 2,
-        "||" => 1,
-        "&&" => 1,
-        "(" | ")" => 0, 
-        _ => 1 
-    } 
-}
+    fn parse_func_def(&mut self) -> Result<Stmt, String> {
+        self.expect(TokKind::Dollar, "Expected '$'")?;
+        let name = self.parse_ident("Expected function name")?;
+        self.expect(TokKind::LBracket, "Expected '[' after function name")?;
+        let func = self.parse_func(Some(name.clone()))?;
+        Ok(Stmt::FuncDef(name, Rc::new(func)))
+    }
 
-fn to_rpn(tokens: Vec<Tok>) -> Result<Vec<Tok>, String> {
-    let mut out = Vec::new();
-    let mut ops: Vec<String> = Vec::new();
-    for t in tokens {
-        match t {
-            Tok::Num(n) => out.push(Tok::Num(n)),
-            Tok::Op(ref op_str) if op_str == "(" => ops.push(op_str.clone()),
-            Tok::Op(ref op_str) if op_str == ")" => {
-                while let Some(op) = ops.pop() {
-                    if op == "(" { break; }
-                    out.push(Tok::Op(op));
-                }
-            },
-            Tok::Op(op_str) => {
-                while let Some(top) = ops.last() {
-                    if (prec(top) > prec(&op_str)) || (prec(top) == prec(&op_str) && &op_str != "^") {
-                        out.push(Tok::Op(top.clone())); 
-                        ops.pop();
-                    } else { break; }
-                }
-                ops.push(op_str);
-            },
+    /// Parameters after the opening '[' and the body of a function
+    fn parse_func(&mut self, name: Option<String>) -> Result<Func, String> {
+        let mut params = Vec::new();
+        while !self.eat(&TokKind::RBracket) {
+            let msg = match &name {
+                Some(name) => format!("Invalid parameter in definition of function '{}'", name),
+                None => "Invalid parameter in anonymous function".to_string(),
+            };
+            params.push(self.parse_ident(&msg)?);
+            if !self.eat(&TokKind::Comma) && !self.at(&TokKind::RBracket) {
+                return self.err("Expected ',' or ']' in parameter list");
+            }
         }
+        // the body's statements must not change how `>` is read around the function
+        let outer = self.gt_is_send;
+        let body = self.parse_block("function parameters");
+        self.gt_is_send = outer;
+        Ok(Func { name, params, body: body?, module: self.module })
     }
-    while let Some(op) = ops.pop() { out.push(Tok::Op(op)); }
-    Ok(out)
-}
 
-fn eval_rpn(rpn: Vec<Tok>) -> Result<i64, String> {
-    let mut st: Vec<i64> = Vec::new();
-    for t in rpn {
-        match t {
-            Tok::Num(n) => st.push(n),
-            Tok::Op(op) => {
-                let b = st.pop().ok_or(format!("Evaluation error: not enough operands for operator '{}'", op))?;
-                let a = st.pop().ok_or(format!("Evaluation error: not enough operands for operator '{}'", op))?;
-                let res = match op.as_str() {
-                    "+" => a + b,
-                    "-" => a - b,
-                    "*" => a * b,
-                    "/" => {
-                        if b == 0 {
-                            return Err("Division by zero".to_string());
-                        }
-                        a / b
-                    },
-                    "%" => {
-                        if b == 0 {
-                            return Err("Modulo by zero".to_string());
-                        }
-                        a % b
-                    },
-                    "^" => a.pow(b as u32),
-                    "=" => if a == b { 1 } else { 0 },
-                    "<" => if a < b { 1 } else { 0 },
-                    ">" => if a > b { 1 } else { 0 },
-                    "<=" => if a <= b { 1 } else { 0 },
-                    ">=" => if a >= b { 1 } else { 0 },
-                    "||" => if a != 0 || b != 0 { 1 } else { 0 },
-                    "&&" => if a != 0 && b != 0 { 1 } else { 0 },
-                    _ => return Err(format!("Unknown operator: '{}'", op)),
-                };
-                st.push(res);
-            },
+    fn parse_import(&mut self) -> Result<Stmt, String> {
+        self.expect(TokKind::Hash, "Expected '#'")?;
+        let path = match self.peek() {
+            Some(TokKind::Str(path)) => path.clone(),
+            _ => return self.err("Expected a quoted path after '#'"),
+        };
+        // rc has already found and checked the file, this only looks up which one it was
+        match IMPORTS.iter().find(|&&(from, p, _)| from == self.module && p == path) {
+            Some(&(_, _, to)) => { self.pos += 1; Ok(Stmt::Import(to)) }
+            None => self.err(&format!("Import \"{}\" was not resolved when compiling", path)),
         }
     }
-    st.pop().ok_or("Evaluation error: empty expression result".into())
-}
-
-// Helpers for extraction in the simple runner
-fn skip_ws_bytes(b: &[u8], i: &mut usize) { while *i < b.len() && (b[*i] as char).is_whitespace() { *i += 1; } }
 
-fn count_newlines(s: &str) -> usize {
-    s.chars().filter(|&c| c == '\n').count()
-}
+    fn parse_ident(&mut self, msg: &str) -> Result<String, String> {
+        match self.peek() {
+            Some(TokKind::Ident(name)) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => self.err(msg),
+        }
+    }
 
-fn extract_string(s: &str, mut i: usize) -> Result<(String, usize), String> {
-    let bytes = s.as_bytes();
-    if bytes[i] as char != '"' { return Err("not a string".into()); }
-    i += 1;
-    let start = i;
-    while i < bytes.len() && (bytes[i] as char) != '"' { i += 1; }
-    if i >= bytes.len() { return Err("unterminated string".into()); }
-    let lit = s[start..i].to_string();
-    i += 1; // consume end quote
-    Ok((lit, i))
-}
+    /// `expr > a, b` (targets may also be `.` for the console and `&` for return)
+    fn parse_send(&mut self) -> Result<Stmt, String> {
+        let start = self.pos;
+        self.gt_is_send = true;
+        let expr = self.parse_expr();
+        self.gt_is_send = false;
+        let value = SrcExpr { expr: expr?, src: self.text_from(start), line: self.line_at(start) };
+        let aug = match self.peek() {
+            Some(TokKind::Gt) => None,
+            Some(TokKind::AugSend(op)) => Some(*op),
+            _ => {
+                if let Expr::CallOrIndex(..) = value.expr {
+                    return Ok(Stmt::Call(value));
+                }
+                return self.err(&format!("No '>' operator found in statement: {}", value.src));
+            }
+        };
+        self.pos += 1;
+        let mut targets = Vec::new();
+        loop {
+            let target = match self.peek() {
+                Some(TokKind::Dot) => Target::Console,
+                Some(TokKind::Amp) => Target::Return,
+                Some(TokKind::Bang) => Target::Exit,
+                Some(TokKind::Ident(name)) => Target::Var(name.clone(), false),
+                Some(TokKind::Colon) => match self.peek_at(1) {
+                    Some(TokKind::Ident(name)) => {
+                        let name = name.clone();
+                        self.pos += 1;
+                        Target::Var(name, true)
+                    }
+                    _ => return self.err("Expected a variable name after ':'"),
+                },
+                _ => return self.err("Expected a variable, '.', '&' or '!' after '>'"),
+            };
+            let start = self.pos;
+            self.pos += 1;
+            let target = match target {
+                Target::Var(name, global) if self.adjacent_bracket() => {
+                    let chain = self.parse_indices(Expr::Var(name), start)?;
+                    Target::Index(chain, self.text_from(start), global)
+                }
+                t => t,
+            };
+            targets.push(target);
+            // `,[` on the next line starts a list literal, not another target
+            if !self.at(&TokKind::Comma) || self.peek_at(1) == Some(&TokKind::LBracket) { break; }
+            self.pos += 1;
+        }
+        Ok(Stmt::Send { value, aug, targets })
+    }
 
-fn extract_braced_block(s: &str, mut i: usize) -> Result<(String, usize), String> {
-    let bytes = s.as_bytes();
-    if bytes[i] as char != '{' { return Err("expected '{'".into()); }
-    let open_line = count_newlines(&s[..i]);
-    i += 1; // consume {
-    let mut depth = 1usize;
-    let start = i;
-    while i < bytes.len() {
-        let c = bytes[i] as char;
-        if c == '{' { depth += 1; }
-        else if c == '}' { depth -= 1; if depth == 0 { break; } }
-        i += 1;
+    /// Condition or loop count: an expression in which `>` compares
+    fn parse_cond(&mut self) -> Result<SrcExpr, String> {
+        let start = self.pos;
+        let expr = self.parse_expr()?;
+        Ok(SrcExpr { expr, src: self.text_from(start), line: self.line_at(start) })
     }
-    if i >= bytes.len() { 
-        let current_line = count_newlines(&s[..i]);
-        return Err(format!("unmatched '{{' at line {}, never closed (current line: {})", open_line + 1, current_line + 1)); 
+
+    fn parse_expr(&mut self) -> Result<Expr, String> {
+        self.parse_or()
     }
-    let block = s[start..i].to_string();
-    i += 1; // consume '}'
-    Ok((block, i))
+
+    fn parse_or(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.parse_and()?;
+        while self.eat(&TokKind::OrOr) {
+            let rhs = self.parse_and()?;
+            lhs = Expr::Binary(BinOp::Or, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.parse_cmp()?;
+        while self.eat(&TokKind::AndAnd) {
+            let rhs = self.parse_cmp()?;
+            lhs = Expr::Binary(BinOp::And, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_cmp(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.parse_add()?;
+        loop {
+            let op = match self.peek() {
+                Some(TokKind::Eq) => BinOp::Eq,
+                Some(TokKind::Lt) => BinOp::Lt,
+                Some(TokKind::Le) => BinOp::Le,
+                Some(TokKind::Ge) => BinOp::Ge,
+                Some(TokKind::GtGt) => BinOp::Gt,
+                Some(TokKind::Gt) if !self.gt_is_send => BinOp::Gt,
+                Some(TokKind::Tilde) => BinOp::In,
+                _ => break,
+            };
+            self.pos += 1;
+            let rhs = self.parse_add()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_add(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.parse_mul()?;
+        loop {
+            let op = match self.peek() {
+                Some(TokKind::Plus) => BinOp::Add,
+                Some(TokKind::Minus) => BinOp::Sub,
+                _ => break,
+            };
+            self.pos += 1;
+            let rhs = self.parse_mul()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_mul(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.parse_unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(TokKind::Star) => BinOp::Mul,
+                Some(TokKind::Slash) => BinOp::Div,
+                Some(TokKind::Percent) => BinOp::Mod,
+                _ => break,
+            };
+            self.pos += 1;
+            let rhs = self.parse_unary()?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    /// Negation binds tighter than `*` but looser than `^`, so -2^2 is -4
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        if self.eat(&TokKind::Minus) {
+            let operand = self.parse_unary()?;
+            return Ok(Expr::Unary(UnOp::Neg, Box::new(operand)));
+        }
+        self.parse_pow()
+    }
+
+    /// `^` is right associative and its exponent may be negated: 2^-1
+    fn parse_pow(&mut self) -> Result<Expr, String> {
+        let base = self.parse_primary()?;
+        if self.eat(&TokKind::Caret) {
+            let exp = self.parse_unary()?;
+            return Ok(Expr::Binary(BinOp::Pow, Box::new(base), Box::new(exp)));
+        }
+        Ok(base)
+    }
+
+    /// A bracketed sub-expression, where `>` always compares
+    fn parse_nested(&mut self) -> Result<Expr, String> {
+        let outer = std::mem::replace(&mut self.gt_is_send, false);
+        let expr = self.parse_expr();
+        self.gt_is_send = outer;
+        expr
+    }
+
+    /// `name[...]` only when the bracket directly follows the previous token
+    fn adjacent_bracket(&self) -> bool {
+        match (self.pos.checked_sub(1).and_then(|p| self.toks.get(p)), self.toks.get(self.pos)) {
+            (Some(prev), Some(t)) => t.kind == TokKind::LBracket && t.span.start == prev.span.end,
+            _ => false,
+        }
+    }
+
+    /// Any number of `[index]` suffixes on `base`, which started at token `start`
+    fn parse_indices(&mut self, mut base: Expr, start: usize) -> Result<Expr, String> {
+        while self.adjacent_bracket() {
+            let label = self.text_from(start);
+            self.pos += 1;
+            let args = self.parse_args(&format!("'{}'", label))?;
+            base = Expr::Index(Box::new(base), args, label);
+        }
+        Ok(base)
+    }
+
+    /// Comma separated expressions up to and including the closing ']', the opening '[' is already eaten
+    fn parse_args(&mut self, what: &str) -> Result<Vec<Expr>, String> {
+        let mut args = Vec::new();
+        while !self.eat(&TokKind::RBracket) {
+            args.push(self.parse_nested()?);
+            if !self.eat(&TokKind::Comma) && !self.at(&TokKind::RBracket) {
+                return self.err(&format!("Expected ',' or ']' after argument to {}", what));
+            }
+        }
+        Ok(args)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        let tok = match self.toks.get(self.pos) {
+            Some(t) => t.clone(),
+            None => return self.err("Expected an expression"),
+        };
+        match tok.kind {
+            TokKind::Num(n) => { self.pos += 1; Ok(Expr::Lit(Val::Int(n))) }
+            TokKind::Big(b) => { self.pos += 1; Ok(Expr::Lit(Val::Big(b))) }
+            TokKind::Float(f) => { self.pos += 1; Ok(Expr::Lit(Val::Float(f))) }
+            TokKind::Str(s) => { self.pos += 1; Ok(Expr::Lit(Val::Str(s))) }
+            TokKind::Bool(b) => { self.pos += 1; Ok(Expr::Lit(Val::Bool(b))) }
+            TokKind::Interp(parts) => {
+                let mut pieces = Vec::new();
+                for part in parts {
+                    match part {
+                        StrPart::Text(t) => if !t.is_empty() { pieces.push(Expr::Lit(Val::Str(t))) },
+                        StrPart::Code(src) => {
+                            let expr = parse_embedded(&src)
+                                .or_else(|e| self.err(&format!("In string interpolation '{{{}}}': {}", src, e)))?;
+                            pieces.push(expr);
+                        }
+                    }
+                }
+                self.pos += 1;
+                Ok(Expr::Interp(pieces))
+            }
+            // `!` takes a whole comparison: !a=b is !(a=b)
+            TokKind::Bang => {
+                self.pos += 1;
+                let operand = self.parse_cmp()?;
+                Ok(Expr::Unary(UnOp::Not, Box::new(operand)))
+            }
+            // parentheses, or the optional square brackets around a condition or loop count
+            TokKind::LParen | TokKind::LBracket => {
+                self.pos += 1;
+                let expr = self.parse_nested()?;
+                let close = if tok.kind == TokKind::LParen { TokKind::RParen } else { TokKind::RBracket };
+                self.expect(close, "Unclosed '(' or '[' in expression")?;
+                Ok(expr)
+            }
+            TokKind::Ident(name) => {
+                let start = self.pos;
+                self.pos += 1;
+                if !self.adjacent_bracket() { return Ok(Expr::Var(name)); }
+                self.pos += 1;
+                let args = self.parse_args(&format!("'{}'", name))?;
+                // further indices: f[0][1]
+                self.parse_indices(Expr::CallOrIndex(name, args), start)
+            }
+            // anonymous functions: $[a, b]{body}
+            TokKind::Dollar if self.peek_at(1) == Some(&TokKind::LBracket) => {
+                self.pos += 2;
+                Ok(Expr::Lambda(Rc::new(self.parse_func(None)?)))
+            }
+            // macros: $name[expr, expr]
+            TokKind::Dollar => {
+                self.pos += 1;
+                let name = self.parse_ident("Expected macro name after '$'")?;
+                self.expect(TokKind::LBracket, &format!("Macro expression '${}' missing opening bracket '['", name))?;
+                let args = self.parse_args(&format!("macro ${}", name))?;
+                Ok(Expr::Macro(name, args))
+            }
+            TokKind::Comma if self.peek_at(1) == Some(&TokKind::LBracket) => {
+                self.pos += 2;
+                self.parse_list_literal()
+            }
+            // console reads
+            TokKind::Dot => { self.pos += 1; Ok(Expr::Read(ReadKind::Line)) }
+            TokKind::DotDot => { self.pos += 1; Ok(Expr::Read(ReadKind::All)) }
+            TokKind::DotHash => { self.pos += 1; Ok(Expr::Read(ReadKind::Int)) }
+            TokKind::Colon if self.peek_at(1) == Some(&TokKind::LBracket) => {
+                self.pos += 2;
+                self.parse_map_literal()
+            }
+            _ => self.err("Expected an expression"),
+        }
+    }
+
+    /// `,[a, b + 1, ,[c]]`: every element is an expression, evaluated when the list is built
+    fn parse_list_literal(&mut self) -> Result<Expr, String> {
+        let mut items = Vec::new();
+        while !self.eat(&TokKind::RBracket) {
+            let start = self.pos;
+            let item = self.parse_nested();
+            match item {
+                Ok(e) if matches!(self.peek(), Some(TokKind::Comma) | Some(TokKind::RBracket)) => items.push(e),
+                _ => {
+                    // report the whole element as written
+                    self.pos = start;
+                    while !matches!(self.peek(), None | Some(TokKind::Comma) | Some(TokKind::RBracket)) { self.pos += 1; }
+                    let text = self.text_from(start);
+                    self.pos = start;
+                    return self.err(&format!("Invalid list element '{}': expected an expression", text));
+                }
+            }
+            self.eat(&TokKind::Comma);
+        }
+        Ok(Expr::List(items))
+    }
+
+    /// `:[key: value, ...]`, keys and values are expressions
+    fn parse_map_literal(&mut self) -> Result<Expr, String> {
+        let mut entries = Vec::new();
+        while !self.eat(&TokKind::RBracket) {
+            let key = self.parse_nested()?;
+            self.expect(TokKind::Colon, "Expected ':' between a map key and its value")?;
+            let value = self.parse_nested()?;
+            entries.push((key, value));
+            if !self.eat(&TokKind::Comma) && !self.at(&TokKind::RBracket) {
+                return self.err("Expected ',' or ']' after map entry");
+            }
+        }
+        Ok(Expr::Map(entries))
+    }
+}
+
+/// The expression inside `{...}` in a string literal
+fn parse_embedded(src: &str) -> Result<Expr, String> {
+    let tokens = lex(src)?;
+    if tokens.is_empty() {
+        return Err("expected an expression".into());
+    }
+    let mut parser = Parser::new(src, tokens);
+    let expr = parser.parse_nested()?;
+    if parser.pos < parser.toks.len() {
+        return parser.err("unexpected text after the expression");
+    }
+    Ok(expr)
 }
 
-fn extract_targets(s: &str, mut i: usize) -> Result<(Vec<String>, usize), String> {
-    let bytes = s.as_bytes();
-    skip_ws_bytes(bytes, &mut i);
-    let start = i;
-    while i < bytes.len() && (bytes[i] as char) != ';' && (bytes[i] as char) != '\n' { i += 1; }
-    let raw = s[start..i].trim();
-    let targets = raw.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect();
-    Ok((targets, i))
+// ---------------------------------------------------------------------------
+// Evaluator: walks the AST
+// ---------------------------------------------------------------------------
+
+/// How a block finished running
+enum Flow {
+    Normal,
+    Break,
+    Continue,
+    Return(Val),
 }
 
-fn handle_if_chain(code: &str, i: &mut usize, vars: &mut HashMap<String, Val>) -> Result<(), String> {
-    let bytes = code.as_bytes();
-    let mut matched = false;
-    loop {
-        skip_ws_bytes(bytes, i);
-        if *i >= bytes.len() { break; }
-        // determine clause type
-        let clause = if bytes[*i] as char == '?' {
-            *i += 1;
-            "if"
-        } else if bytes[*i] as char == '!' {
-            if *i + 1 < bytes.len() && (bytes[*i + 1] as char) == '?' {
-                *i += 2; "elif"
-            } else if *i + 1 < bytes.len() && (bytes[*i + 1] as char) == '!' {
-                *i += 2; "else"
-            } else { return Err("Invalid if-clause".into()); }
-        } else { break; };
-
-        skip_ws_bytes(bytes, i);
-        let truth = if clause != "else" {
-            // read until '{' as expression
-            let start_expr = *i;
-            while *i < bytes.len() && (bytes[*i] as char) != '{' { *i += 1; }
-            let expr_str = code[start_expr..*i].trim();
-            let val = eval_expr(expr_str, vars)?;
-            val.as_i64() != 0
-        } else { true };
-
-        skip_ws_bytes(bytes, i);
-        if *i >= bytes.len() || (bytes[*i] as char) != '{' { return Err("Expected '{' after if condition".into()); }
-        let (block, ni2) = extract_braced_block(code, *i)?;
-        *i = ni2;
-        if !matched && truth {
-            run_block_simple_loop(&block, vars)?;
-            matched = true;
-        }
-
-        // peek for next clause: skip whitespace and check next char
-        skip_ws_bytes(bytes, i);
-        if *i >= bytes.len() { break; }
-        let nextc = bytes[*i] as char;
-        if !(nextc == '?' || (nextc == '!' && *i + 1 < bytes.len() && ((bytes[*i+1] as char)=='?' || (bytes[*i+1] as char)=='!'))) {
-            break;
-        }
-    }
-    Ok(())
+#[derive(Default)]
+struct Interp {
+    /// Variables, innermost last: the globals, then a scope for each running function and block
+    scopes: Vec<HashMap<String, Val>>,
+    /// Where the running function's scopes start, 0 at the top level
+    base: usize,
+    funcs: HashMap<String, Rc<Func>>,
+    /// Value of `_` for every `*` loop we are currently inside, outermost first
+    loop_indices: Vec<Val>,
+    /// Source of the statement expression being evaluated, for operator errors
+    cur_src: Option<Rc<str>>,
+    /// Parsed code of every file, see `MODULES`
+    modules: Vec<Rc<Vec<Stmt>>>,
+    /// Files that have already run
+    imported: Vec<bool>,
+    /// File the running code comes from
+    module: usize,
+    /// Whether the error being returned already says which file it came from
+    error_located: bool,
+    /// Line of the statement being evaluated, for the call stack
+    cur_line: usize,
+    /// Function calls in progress, outermost first
+    calls: Vec<Frame>,
+    max_depth: usize,
+    /// Calls, bodies and expressions being evaluated, bounded so the native stack cannot overflow
+    nesting: usize,
+    max_nesting: usize,
+    /// The calls in progress when the error being returned happened
+    trace: Option<Vec<Frame>>,
+}
+
+/// A function call in progress: what was called and where from
+#[derive(Debug, Clone, PartialEq)]
+struct Frame {
+    name: String,
+    line: usize,
+    module: usize,
+}
+
+impl Interp {
+    fn exec_block(&mut self, stmts: &[Stmt]) -> Result<Flow, String> {
+        for stmt in stmts {
+            let flow = self.exec(stmt)?;
+            if !matches!(flow, Flow::Normal) { return Ok(flow); }
+        }
+        Ok(Flow::Normal)
+    }
+
+    /// Run the body of an if or loop, variables first set inside it are dropped at the end
+    fn exec_scoped(&mut self, stmts: &[Stmt]) -> Result<Flow, String> {
+        self.enter()?;
+        self.scopes.push(HashMap::new());
+        let flow = self.exec_block(stmts);
+        self.scopes.pop();
+        self.nesting -= 1;
+        flow
+    }
+
+    /// Count one more level of evaluator recursion, undone with `self.nesting -= 1`
+    fn enter(&mut self) -> Result<(), String> {
+        if self.nesting >= self.max_nesting {
+            if self.trace.is_none() {
+                self.trace = Some(self.calls.clone());
+            }
+            return Err(format!("maximum nesting depth {} of bodies and expressions exceeded", self.max_nesting));
+        }
+        self.nesting += 1;
+        Ok(())
+    }
+
+    /// Value of a variable: the running function's scopes are searched innermost first, then the globals
+    fn lookup(&self, name: &str) -> Option<&Val> {
+        let globals = self.scopes[..1].iter().filter(|_| self.base > 0);
+        self.scopes[self.base..].iter().rev().chain(globals).find_map(|scope| scope.get(name))
+    }
+
+    /// Scope a send to `name` writes to when the variable exists: `:name` only looks at the globals,
+    /// otherwise the innermost of the running function's scopes that has it
+    fn target_scope(&self, name: &str, global: bool) -> Option<usize> {
+        if global {
+            return if self.scopes[0].contains_key(name) { Some(0) } else { None };
+        }
+        self.scopes[self.base..].iter().rposition(|scope| scope.contains_key(name)).map(|i| i + self.base)
+    }
+
+    fn exec(&mut self, stmt: &Stmt) -> Result<Flow, String> {
+        match stmt {
+            Stmt::Send { value, aug, targets } => {
+                let val = self.eval_src(value)?;
+                for target in targets {
+                    match target {
+                        Target::Console => println!("{}", val.as_string()),
+                        Target::Return => {
+                            if aug.is_some() {
+                                return Err(format!("Cannot use augmented operator with '&' in statement: {}", value.src));
+                            }
+                            return Ok(Flow::Return(val));
+                        }
+                        Target::Exit => {
+                            let code = match val {
+                                Val::Int(n) if n >= i32::MIN as i64 && n <= i32::MAX as i64 => n as i32,
+                                Val::Int(_) | Val::Big(_) => return Err(format!("Exit status must fit in a 32-bit integer, got {}", val.repr())),
+                                _ => return Err(format!("Exit status must be an integer, got {} {}", val.type_name(), val.repr())),
+                            };
+                            io::stdout().flush().ok();
+                            std::process::exit(code);
+                        }
+                        Target::Var(name, global) => {
+                            // a new variable goes in the innermost scope, but a new total (`_ +> t`) goes in
+                            // the function's outermost one (the globals at the top level) so it outlives the loop
+                            let fresh = if *global { 0 } else if aug.is_some() { self.base } else { self.scopes.len() - 1 };
+                            let scope = self.target_scope(name, *global).unwrap_or(fresh);
+                            let newv = match aug {
+                                None => val.clone(),
+                                Some(op) => {
+                                    // augmented: variable = variable (op) value, the value is moved out so a list is not copied
+                                    let cur = self.scopes[scope].remove(name).unwrap_or_else(|| fresh_total(&val));
+                                    augment(cur, val.clone(), *op).map_err(|e| {
+                                        format!("In '{} {}> {}': {}", value.src, op.symbol(), name, e)
+                                    })?
+                                }
+                            };
+                            self.scopes[scope].insert(name.clone(), newv);
+                        }
+                        Target::Index(chain, src, global) => {
+                            let missing = aug.map_or(Val::Int(0), |_| fresh_total(&val));
+                            let slot = self.index_slot(chain, *global, missing)?;
+                            let newv = match aug {
+                                None => val.clone(),
+                                Some(op) => augment(std::mem::replace(slot, Val::Int(0)), val.clone(), *op).map_err(|e| {
+                                    format!("In '{} {}> {}': {}", value.src, op.symbol(), src, e)
+                                })?,
+                            };
+                            *slot = newv;
+                        }
+                    }
+                }
+                Ok(Flow::Normal)
+            }
+            Stmt::Call(call) => {
+                if let Expr::CallOrIndex(name, _) = &call.expr {
+                    let is_call = match self.lookup(name) {
+                        Some(v) => matches!(v, Val::Func(_)),
+                        None => self.funcs.contains_key(name),
+                    };
+                    if !is_call {
+                        return Err(format!("No '>' operator found in statement: {}", call.src));
+                    }
+                }
+                self.eval_src(call)?;
+                Ok(Flow::Normal)
+            }
+            Stmt::If(clauses) => {
+                for (cond, body) in clauses {
+                    let truth = match cond {
+                        Some(c) => self.eval_src(c)?.is_truthy(),
+                        None => true,
+                    };
+                    if truth { return self.exec_scoped(body); }
+                }
+                Ok(Flow::Normal)
+            }
+            Stmt::For { count, body } => {
+                // a map runs once per entry with `_` set to [key, value]
+                let (num, entries) = match self.eval_src(count)? {
+                    Val::Map(map) => {
+                        let entries: Vec<Val> = map.into_iter().map(|(k, v)| Val::List(vec![k.to_val(), v])).collect();
+                        (entries.len() as i64, Some(entries))
+                    }
+                    n => (n.as_i64(), None),
+                };
+                self.loop_indices.push(Val::Int(0));
+                let mut flow = Flow::Normal;
+                for idx in 0..num {
+                    self.set_loop_value(match &entries {
+                        Some(entries) => entries[idx as usize].clone(),
+                        None => Val::Int(idx),
+                    });
+                    match self.exec_scoped(body)? {
+                        Flow::Break => break,
+                        Flow::Return(v) => { flow = Flow::Return(v); break; }
+                        Flow::Normal | Flow::Continue => {}
+                    }
+                }
+                self.loop_indices.pop();
+                Ok(flow)
+            }
+            Stmt::While { cond, body } => {
+                // while loop: keep executing block while condition is true
+                self.loop_indices.push(Val::Int(0));
+                let mut idx = 0;
+                let mut flow = Flow::Normal;
+                loop {
+                    // keep _ as working
+                    self.set_loop_value(Val::Int(idx));
+                    if !self.eval_src(cond)?.is_truthy() {
+                        break;
+                    }
+                    idx += 1;
+                    match self.exec_scoped(body)? {
+                        Flow::Break => break,
+                        Flow::Return(v) => { flow = Flow::Return(v); break; }
+                        Flow::Normal | Flow::Continue => {}
+                    }
+                }
+                self.loop_indices.pop();
+                Ok(flow)
+            }
+            Stmt::FuncDef(name, func) => {
+                self.funcs.insert(name.clone(), func.clone());
+                Ok(Flow::Normal)
+            }
+            Stmt::Import(module) => {
+                // a file runs once, importing it again just gives access to what it defined
+                if !self.imported[*module] {
+                    self.imported[*module] = true;
+                    let program = self.modules[*module].clone();
+                    // its top level runs next to the globals, whatever scope the import is in
+                    let scopes = self.scopes.split_off(1);
+                    let base = std::mem::replace(&mut self.base, 0);
+                    let loops = std::mem::take(&mut self.loop_indices);
+                    let result = self.in_module(*module, |interp| {
+                        let flow = interp.exec_block(&program)?;
+                        top_level(flow)
+                    });
+                    self.scopes.extend(scopes);
+                    self.base = base;
+                    self.loop_indices = loops;
+                    result?;
+                }
+                Ok(Flow::Normal)
+            }
+            Stmt::Break => Ok(Flow::Break),
+            Stmt::Continue => Ok(Flow::Continue),
+        }
+    }
+
+    /// Run `f` as code from file `module`, naming that file in an error that leaves it
+    fn in_module<T, F: FnOnce(&mut Self) -> Result<T, String>>(&mut self, module: usize, f: F) -> Result<T, String> {
+        let outer = std::mem::replace(&mut self.module, module);
+        let result = f(self);
+        self.module = outer;
+        result.map_err(|e| {
+            if module == outer || self.error_located {
+                return e;
+            }
+            self.error_located = true;
+            match module {
+                0 => e,
+                k => format!("{}: {}", MODULES[k - 1].0, e),
+            }
+        })
+    }
+
+    /// The list element an indexed target refers to, indices are evaluated outermost first
+    /// (a missing last map key is created holding `missing`)
+    fn index_slot(&mut self, chain: &Expr, global: bool, missing: Val) -> Result<&mut Val, String> {
+        let mut steps = Vec::new();
+        let mut e = chain;
+        while let Expr::Index(list, args, label) = e {
+            if args.len() != 1 {
+                return Err(format!("Cannot assign into '{}': a list index takes exactly one value", label));
+            }
+            steps.push((&args[0], label));
+            e = list;
+        }
+        let name = match e {
+            Expr::Var(name) => name,
+            _ => unreachable!(),
+        };
+        let mut path = Vec::new();
+        for (index, label) in steps.into_iter().rev() {
+            path.push((self.eval(index)?, label));
+        }
+        let last = path.len() - 1;
+        let scope = match self.target_scope(name, global) {
+            Some(scope) => scope,
+            None if !global && self.base > 0 && self.scopes[0].contains_key(name) => {
+                return Err(format!("Cannot assign into '{}' inside a function, use ':{}' to change the global", name, name));
+            }
+            None => return Err(format!("Cannot assign into '{}': it is not a list or map", name)),
+        };
+        let mut slot = match self.scopes[scope].get_mut(name) {
+            Some(v) => v,
+            None => unreachable!(),
+        };
+        for (step, (index, label)) in path.into_iter().enumerate() {
+            slot = match slot {
+                Val::List(items) => {
+                    let i = list_index(&index, label, items.len())?;
+                    &mut items[i]
+                }
+                // the last key is created if missing, like an unset variable
+                Val::Map(map) => {
+                    let key = Key::from_val(&index)?;
+                    if step < last && !map.contains_key(&key) {
+                        return Err(format!("Key {} not found in map '{}'", index.repr(), label));
+                    }
+                    map.entry(key).or_insert_with(|| missing.clone())
+                }
+                _ => return Err(format!("Cannot assign into '{}': it is not a list or map", label)),
+            };
+        }
+        Ok(slot)
+    }
+
+    /// Set the innermost loop value
+    fn set_loop_value(&mut self, val: Val) {
+        if let Some(last) = self.loop_indices.last_mut() { *last = val; }
+    }
+
+    /// `_` is a plain value in a single loop and a list of all values when nested
+    fn loop_value(&self) -> Option<Val> {
+        match self.loop_indices.len() {
+            0 => None,
+            1 => Some(self.loop_indices[0].clone()),
+            _ => Some(Val::List(self.loop_indices.clone())),
+        }
+    }
+
+    /// Evaluate a statement's expression, remembering its source for error messages
+    fn eval_src(&mut self, e: &SrcExpr) -> Result<Val, String> {
+        let outer = self.cur_src.replace(e.src.clone());
+        let outer_line = std::mem::replace(&mut self.cur_line, e.line);
+        let val = self.eval(&e.expr);
+        self.cur_src = outer;
+        self.cur_line = outer_line;
+        val
+    }
+
+    fn in_expr(&self, msg: String) -> String {
+        match &self.cur_src {
+            Some(src) => format!("In expression '{}': {}", src, msg),
+            None => msg,
+        }
+    }
+
+    fn eval(&mut self, e: &Expr) -> Result<Val, String> {
+        self.enter()?;
+        let val = self.eval_expr(e);
+        self.nesting -= 1;
+        val
+    }
+
+    fn eval_expr(&mut self, e: &Expr) -> Result<Val, String> {
+        match e {
+            Expr::Lit(v) => Ok(v.clone()),
+            Expr::Var(name) if name == "_" && !self.loop_indices.is_empty() => Ok(self.loop_value().unwrap_or(Val::Int(0))),
+            Expr::Var(name) => match self.lookup(name) {
+                Some(v) => Ok(v.clone()),
+                // a named function used as a value
+                None => Ok(match self.funcs.get(name) {
+                    Some(func) => Val::Func(Rc::new(Closure { func: func.clone(), captured: HashMap::new() })),
+                    None => Val::Int(0),
+                }),
+            },
+            Expr::CallOrIndex(name, args) => {
+                // variables, parameters included, come before the named functions
+                match self.lookup(name) {
+                    Some(Val::Func(closure)) => {
+                        let closure = closure.clone();
+                        let vals = self.eval_args(args)?;
+                        return self.invoke(&closure, vals, name);
+                    }
+                    None if self.funcs.contains_key(name) => return self.call(name, args),
+                    _ => {}
+                }
+                if args.len() != 1 {
+                    return Err(self.in_expr(format!("'{}' is not a function and a list index takes exactly one value", name)));
+                }
+                let index = self.eval(&args[0])?;
+                let list = match name.as_str() {
+                    "_" => self.loop_value(),
+                    _ => None,
+                };
+                match list.as_ref().or_else(|| self.lookup(name)) {
+                    Some(list) => index_value(list, &index, name).map_err(|e| self.in_expr(e)),
+                    None => Err(self.in_expr(format!("Unknown function or list '{}'", name))),
+                }
+            }
+            Expr::List(items) => {
+                let mut vals = Vec::with_capacity(items.len());
+                for item in items {
+                    vals.push(self.eval(item)?);
+                }
+                Ok(Val::List(vals))
+            }
+            Expr::Map(entries) => {
+                let mut map = BTreeMap::new();
+                for (key, value) in entries {
+                    let key = Key::from_val(&self.eval(key)?).map_err(|e| self.in_expr(e))?;
+                    map.insert(key, self.eval(value)?);
+                }
+                Ok(Val::Map(map))
+            }
+            Expr::Read(kind) => read_console(*kind).map_err(|e| self.in_expr(e)),
+            Expr::Interp(pieces) => {
+                let mut out = String::new();
+                for piece in pieces {
+                    out.push_str(&self.eval(piece)?.as_string());
+                }
+                Ok(Val::Str(out))
+            }
+            Expr::Index(list, args, label) => {
+                let list = self.eval(list)?;
+                let mut vals = self.eval_args(args)?;
+                // `fs[0][x, y]` calls the function stored in fs[0] with x and y
+                if let Val::Func(closure) = list {
+                    return self.invoke(&closure, vals, label);
+                }
+                if vals.len() != 1 {
+                    return Err(self.in_expr(format!("'{}' is not a function and a list index takes exactly one value", label)));
+                }
+                index_value(&list, &vals.pop().unwrap(), label).map_err(|e| self.in_expr(e))
+            }
+            Expr::Macro(name, args) => {
+                let vals = self.eval_args(args)?;
+                let src = self.cur_src.clone();
+                eval_macro(self, name, vals, src.as_deref().unwrap_or(""))
+            }
+            Expr::Lambda(func) => {
+                // capture the variables around it, apart from the globals which stay shared
+                let mut captured = HashMap::new();
+                for scope in &self.scopes[self.base.max(1)..] {
+                    captured.extend(scope.iter().map(|(k, v)| (k.clone(), v.clone())));
+                }
+                if let Some(v) = self.loop_value() {
+                    captured.insert("_".to_string(), v);
+                }
+                Ok(Val::Func(Rc::new(Closure { func: func.clone(), captured })))
+            }
+            Expr::Unary(UnOp::Not, operand) => Ok(Val::Bool(!self.eval(operand)?.is_truthy())),
+            Expr::Unary(UnOp::Neg, operand) => {
+                let v = self.eval(operand)?;
+                match num_operand(&v, BinOp::Sub).map_err(|e| self.in_expr(e))? {
+                    Val::Float(f) => Ok(Val::Float(-f)),
+                    Val::Big(b) => Ok(b.negate().into_val()),
+                    n => {
+                        let n = n.as_i64();
+                        Ok(n.checked_neg().map_or_else(|| BigInt::from_i64(n).negate().into_val(), Val::Int))
+                    }
+                }
+            }
+            Expr::Binary(BinOp::And, a, b) => {
+                let a = self.eval(a)?.is_truthy();
+                Ok(Val::Bool(a && self.eval(b)?.is_truthy()))
+            }
+            Expr::Binary(BinOp::Or, a, b) => {
+                let a = self.eval(a)?.is_truthy();
+                Ok(Val::Bool(a || self.eval(b)?.is_truthy()))
+            }
+            Expr::Binary(op, a, b) => {
+                let a = self.eval(a)?;
+                let b = self.eval(b)?;
+                binary_op(*op, a, b).map_err(|e| self.in_expr(e))
+            }
+        }
+    }
+
+    fn call(&mut self, name: &str, args: &[Expr]) -> Result<Val, String> {
+        let func = self.funcs.get(name).cloned().ok_or(format!("Unknown function '{}'", name))?;
+        if args.len() != func.params.len() {
+            return Err(format!("Function '{}' expects {} argument(s), got {}", name, func.params.len(), args.len()));
+        }
+        // arguments are evaluated in the caller's scope, then bound in a fresh one
+        let vals = self.eval_args(args)?;
+        self.invoke(&Closure { func, captured: HashMap::new() }, vals, name)
+    }
+
+    fn eval_args(&mut self, args: &[Expr]) -> Result<Vec<Val>, String> {
+        let mut vals = Vec::with_capacity(args.len());
+        for arg in args {
+            vals.push(self.eval(arg)?);
+        }
+        Ok(vals)
+    }
+
+    /// Run a function with its arguments already evaluated, `name` is how the caller refers to it
+    fn invoke(&mut self, closure: &Closure, args: Vec<Val>, name: &str) -> Result<Val, String> {
+        let func = closure.func.clone();
+        if args.len() != func.params.len() {
+            return Err(format!("Function '{}' expects {} argument(s), got {}", name, func.params.len(), args.len()));
+        }
+        let frame = Frame { name: name.to_string(), line: self.cur_line, module: self.module };
+        if self.calls.len() >= self.max_depth {
+            self.trace = Some(self.calls.clone());
+            return Err(format!("maximum call depth {} exceeded", self.max_depth));
+        }
+        self.enter()?;
+        self.calls.push(frame);
+        let mut locals = closure.captured.clone();
+        locals.extend(func.params.iter().cloned().zip(args));
+        // the function sees its own scope and the globals, not the caller's variables
+        self.scopes.push(locals);
+        let caller_base = std::mem::replace(&mut self.base, self.scopes.len() - 1);
+        // loops around the call site are not visible through `_` inside the function
+        let caller_loops = std::mem::take(&mut self.loop_indices);
+        let flow = self.in_module(func.module, |interp| interp.exec_block(&func.body));
+        self.scopes.truncate(self.base);
+        self.base = caller_base;
+        self.loop_indices = caller_loops;
+        if flow.is_err() && self.trace.is_none() {
+            self.trace = Some(self.calls.clone());
+        }
+        self.calls.pop();
+        self.nesting -= 1;
+        match flow? {
+            Flow::Return(v) => Ok(v),
+            Flow::Normal => Ok(Val::Int(0)),
+            Flow::Break | Flow::Continue => Err(format!("Break or continue outside of a loop in function '{}'", name)),
+        }
+    }
+}
+
+/// Read from stdin, end of input gives `?-`
+fn read_console(kind: ReadKind) -> Result<Val, String> {
+    let stdin = io::stdin();
+    let mut text = String::new();
+    let read = match kind {
+        ReadKind::All => stdin.lock().read_to_string(&mut text),
+        ReadKind::Line | ReadKind::Int => stdin.lock().read_line(&mut text),
+    };
+    match read {
+        Ok(0) => return Ok(Val::Bool(false)),
+        Ok(_) => {}
+        Err(e) => return Err(format!("Failed to read input: {}", e)),
+    }
+    match kind {
+        ReadKind::All => Ok(Val::Str(text)),
+        ReadKind::Line => Ok(Val::Str(text.trim_end_matches(|c| c == '\n' || c == '\r').to_string())),
+        ReadKind::Int => macro_number(vec![Val::Str(text.trim().to_string())])
+            .map_err(|_| format!("Expected an integer on input, got \"{}\"", text.trim())),
+    }
+}
+
+/// `list[index]` for lists and maps, anything else has no elements
+fn index_value(list: &Val, index: &Val, label: &str) -> Result<Val, String> {
+    match list {
+        Val::List(items) => Ok(items[list_index(index, label, items.len())?].clone()),
+        Val::Map(map) => map.get(&Key::from_val(index)?).cloned()
+            .ok_or_else(|| format!("Key {} not found in map '{}'", index.repr(), label)),
+        _ => Err(format!("Cannot index '{}': {} {} is not a list or map", label, list.type_name(), list.repr())),
+    }
+}
+
+/// Position in a list of length `len` for an index value, only integers are accepted
+fn list_index(index: &Val, label: &str, len: usize) -> Result<usize, String> {
+    let slot = match index {
+        Val::Int(i) => list_slot(len, *i),
+        // never in bounds
+        Val::Big(_) => None,
+        _ => return Err(format!("Index into list '{}' must be an integer, got {} {}", label, index.type_name(), index.repr())),
+    };
+    slot.ok_or_else(|| format!("Index {} out of bounds for list '{}' of length {}", index.as_string(), label, len))
+}
+
+/// Position of `index` in a list of length `len`, negative indices count from the end
+fn list_slot(len: usize, index: i64) -> Option<usize> {
+    let idx = if index < 0 { len as i64 + index } else { index };
+    if idx >= 0 && (idx as usize) < len { Some(idx as usize) } else { None }
+}
+
+/// Numeric value of an operand for arithmetic and ordering (an Int or a Float), booleans are rejected
+fn num_operand(v: &Val, op: BinOp) -> Result<Val, String> {
+    match v {
+        Val::Int(_) | Val::Float(_) | Val::Big(_) => Ok(v.clone()),
+        Val::Bool(_) | Val::Str(_) | Val::List(_) | Val::Map(_) | Val::Func(_) => {
+            Err(format!("Type error: operator '{}' cannot be used with {} {}", op.symbol(), v.type_name(), v.repr()))
+        }
+    }
+}
+
+/// `=` between lists or maps and between their elements: same shape and equal elements,
+/// where values of different types are simply not equal
+fn values_equal(a: &Val, b: &Val) -> bool {
+    match (a, b) {
+        (Val::List(x), Val::List(y)) => x.len() == y.len() && x.iter().zip(y).all(|(p, q)| values_equal(p, q)),
+        (Val::Map(x), Val::Map(y)) => x.len() == y.len() && x.iter().zip(y).all(|((kp, p), (kq, q))| kp == kq && values_equal(p, q)),
+        (Val::Bool(x), Val::Bool(y)) => x == y,
+        (Val::Str(x), Val::Str(y)) => x == y,
+        (Val::Func(x), Val::Func(y)) => Rc::ptr_eq(x, y),
+        _ if a.is_number() && b.is_number() => matches!(arith(BinOp::Eq, a.clone(), b.clone()), Ok(Val::Bool(true))),
+        _ => false,
+    }
+}
+
+fn binary_op(op: BinOp, a: Val, b: Val) -> Result<Val, String> {
+    // logical operators and equality work on booleans directly
+    match (op, &a, &b) {
+        (BinOp::And, _, _) => return Ok(Val::Bool(a.is_truthy() && b.is_truthy())),
+        (BinOp::Or, _, _) => return Ok(Val::Bool(a.is_truthy() || b.is_truthy())),
+        (BinOp::In, _, _) => return contains(&b, &a).map(Val::Bool),
+        (BinOp::Eq, Val::Bool(x), Val::Bool(y)) => return Ok(Val::Bool(x == y)),
+        (BinOp::Eq, Val::List(_), Val::List(_)) | (BinOp::Eq, Val::Map(_), Val::Map(_)) => return Ok(Val::Bool(values_equal(&a, &b))),
+        // a boolean never equals a value of another type, so `n = ?-` tells the end of input from a 0
+        (BinOp::Eq, Val::Bool(_), _) | (BinOp::Eq, _, Val::Bool(_)) => return Ok(Val::Bool(false)),
+        (_, Val::Str(_), _) | (_, _, Val::Str(_)) => return string_op(op, a, b),
+        _ => {}
+    }
+    arith(op, num_operand(&a, op)?, num_operand(&b, op)?)
+}
+
+/// `item ~ container`: a key of a map, an element of a list or a substring of a string
+fn contains(container: &Val, item: &Val) -> Result<bool, String> {
+    match (container, item) {
+        (Val::Map(map), _) => Ok(map.contains_key(&Key::from_val(item)?)),
+        (Val::List(items), _) => Ok(items.iter().any(|v| values_equal(v, item))),
+        (Val::Str(s), Val::Str(sub)) => Ok(s.contains(sub.as_str())),
+        _ => Err(format!("Type error: operator '~' cannot look for {} {} in {} {}", item.type_name(), item.repr(), container.type_name(), container.repr())),
+    }
+}
+
+/// Limit on the size of a string built by `*`, longer results are reported as overflow
+const MAX_STRING_BYTES: usize = 1 << 30;
+
+/// Operators with a string on at least one side
+fn string_op(op: BinOp, a: Val, b: Val) -> Result<Val, String> {
+    match (op, &a, &b) {
+        // a string joins with anything but a list
+        (BinOp::Add, Val::List(_), _) | (BinOp::Add, _, Val::List(_)) | (BinOp::Add, Val::Map(_), _) | (BinOp::Add, _, Val::Map(_)) => {
+            type_mismatch(op, &a, &b)
+        }
+        (BinOp::Add, _, _) => Ok(Val::Str(format!("{}{}", a.as_string(), b.as_string()))),
+        // repetition: "ab" * 3 or 3 * "ab"
+        (BinOp::Mul, Val::Str(st), Val::Int(n)) | (BinOp::Mul, Val::Int(n), Val::Str(st)) => {
+            if *n < 0 {
+                return Err(format!("Cannot repeat string \"{}\" a negative number of times ({})", st, n));
+            }
+            match st.len().checked_mul(*n as usize) {
+                Some(len) if len <= MAX_STRING_BYTES => Ok(Val::Str(st.repeat(*n as usize))),
+                _ => Err(format!("Overflow: repeating string \"{}\" {} times is too long", st, n)),
+            }
+        }
+        // a string is never equal to a value of another type
+        (BinOp::Eq, Val::Str(x), Val::Str(y)) => Ok(Val::Bool(x == y)),
+        (BinOp::Eq, _, _) => Ok(Val::Bool(false)),
+        (BinOp::Lt, Val::Str(x), Val::Str(y)) => Ok(Val::Bool(x < y)),
+        (BinOp::Gt, Val::Str(x), Val::Str(y)) => Ok(Val::Bool(x > y)),
+        (BinOp::Le, Val::Str(x), Val::Str(y)) => Ok(Val::Bool(x <= y)),
+        (BinOp::Ge, Val::Str(x), Val::Str(y)) => Ok(Val::Bool(x >= y)),
+        _ => type_mismatch(op, &a, &b),
+    }
+}
+
+fn type_mismatch(op: BinOp, a: &Val, b: &Val) -> Result<Val, String> {
+    Err(format!(
+        "Type error: operator '{}' cannot be used between {} {} and {} {}",
+        op.symbol(), a.type_name(), a.repr(), b.type_name(), b.repr()
+    ))
+}
+
+/// Arithmetic and ordering on two numbers (Int, Big or Float)
+fn arith(op: BinOp, a: Val, b: Val) -> Result<Val, String> {
+    match (a, b) {
+        (Val::Int(a), Val::Int(b)) => int_op(op, a, b),
+        (Val::Int(a), Val::Big(b)) => big_op(op, &BigInt::from_i64(a), &b),
+        (Val::Big(a), Val::Int(b)) => big_op(op, &a, &BigInt::from_i64(b)),
+        (Val::Big(a), Val::Big(b)) => big_op(op, &a, &b),
+        // anything mixed with a float is promoted to float
+        (a, b) => float_op(op, a.as_f64(), b.as_f64()),
+    }
+}
+
+fn int_op(op: BinOp, a: i64, b: i64) -> Result<Val, String> {
+    let result = match op {
+        BinOp::Add => a.checked_add(b),
+        BinOp::Sub => a.checked_sub(b),
+        BinOp::Mul => a.checked_mul(b),
+        BinOp::Div => {
+            if b == 0 {
+                return Err("Division by zero".to_string());
+            }
+            // an inexact division gives a float: 7 / 2 is 3.5
+            if a.checked_rem(b).map_or(false, |r| r != 0) {
+                return float_op(op, a as f64, b as f64);
+            }
+            a.checked_div(b)
+        }
+        BinOp::Mod => {
+            if b == 0 {
+                return Err("Modulo by zero".to_string());
+            }
+            a.checked_rem(b)
+        }
+        BinOp::Pow if b < 0 => return Err(format!("Negative exponent in {} ^ {}", a, b)),
+        BinOp::Pow if b > u32::MAX as i64 => None,
+        BinOp::Pow => a.checked_pow(b as u32),
+        BinOp::Eq => return Ok(Val::Bool(a == b)),
+        BinOp::Lt => return Ok(Val::Bool(a < b)),
+        BinOp::Gt => return Ok(Val::Bool(a > b)),
+        BinOp::Le => return Ok(Val::Bool(a <= b)),
+        BinOp::Ge => return Ok(Val::Bool(a >= b)),
+        BinOp::In | BinOp::And | BinOp::Or => unreachable!(),
+    };
+    // results that do not fit in an i64 are redone with big integers
+    match result {
+        Some(n) => Ok(Val::Int(n)),
+        None => big_op(op, &BigInt::from_i64(a), &BigInt::from_i64(b)),
+    }
+}
+
+/// Limit on the size of a `^` result, larger powers are reported as overflow
+const MAX_POW_DIGITS: f64 = 100_000.0;
+
+fn big_op(op: BinOp, a: &BigInt, b: &BigInt) -> Result<Val, String> {
+    let zero = BigInt::from_i64(0);
+    Ok(match op {
+        BinOp::Add => a.add(b).into_val(),
+        BinOp::Sub => a.sub(b).into_val(),
+        BinOp::Mul => a.mul(b).into_val(),
+        BinOp::Div => {
+            if *b == zero {
+                return Err("Division by zero".to_string());
+            }
+            let (q, r) = a.div_rem(b);
+            if r != zero {
+                return float_op(op, a.to_f64(), b.to_f64());
+            }
+            q.into_val()
+        }
+        BinOp::Mod => {
+            if *b == zero {
+                return Err("Modulo by zero".to_string());
+            }
+            a.div_rem(b).1.into_val()
+        }
+        BinOp::Pow => {
+            if b.neg {
+                return Err(format!("Negative exponent in {} ^ {}", a, b));
+            }
+            // 0, 1 and -1 stay small for any exponent
+            let odd = b.mag.first().map_or(false, |d| d % 2 == 1);
+            match a.to_i64() {
+                Some(0) => return Ok(Val::Int(if *b == zero { 1 } else { 0 })),
+                Some(1) => return Ok(Val::Int(1)),
+                Some(-1) => return Ok(Val::Int(if odd { -1 } else { 1 })),
+                _ => {}
+            }
+            if b.to_f64() * a.to_f64().abs().log10() > MAX_POW_DIGITS {
+                return Err(format!("Overflow: {} ^ {} has too many digits", a, b));
+            }
+            a.pow(b.to_i64().unwrap_or(0) as u32).into_val()
+        }
+        BinOp::Eq => Val::Bool(a == b),
+        BinOp::Lt => Val::Bool(a < b),
+        BinOp::Gt => Val::Bool(a > b),
+        BinOp::Le => Val::Bool(a <= b),
+        BinOp::Ge => Val::Bool(a >= b),
+        BinOp::In | BinOp::And | BinOp::Or => unreachable!(),
+    })
+}
+
+fn float_op(op: BinOp, a: f64, b: f64) -> Result<Val, String> {
+    let result = match op {
+        BinOp::Add => a + b,
+        BinOp::Sub => a - b,
+        BinOp::Mul => a * b,
+        BinOp::Div => {
+            if b == 0.0 {
+                return Err("Division by zero".to_string());
+            }
+            a / b
+        }
+        BinOp::Mod => {
+            if b == 0.0 {
+                return Err("Modulo by zero".to_string());
+            }
+            a % b
+        }
+        BinOp::Pow => a.powf(b),
+        BinOp::Eq => return Ok(Val::Bool(a == b)),
+        BinOp::Lt => return Ok(Val::Bool(a < b)),
+        BinOp::Gt => return Ok(Val::Bool(a > b)),
+        BinOp::Le => return Ok(Val::Bool(a <= b)),
+        BinOp::Ge => return Ok(Val::Bool(a >= b)),
+        BinOp::In | BinOp::And | BinOp::Or => unreachable!(),
+    };
+    if result.is_infinite() && a.is_finite() && b.is_finite() {
+        return Err(format!("Overflow: {} {} {} is out of float range", Val::Float(a).as_string(), op.symbol(), Val::Float(b).as_string()));
+    }
+    Ok(Val::Float(result))
+}
+
+/// Value an unset variable or map entry has before `val` is sent to it with an augmented send:
+/// 0 for a number, "" for a string ("x" +> s makes "x") and an empty list for anything else
+fn fresh_total(val: &Val) -> Val {
+    match val {
+        Val::Str(_) => Val::Str(String::new()),
+        Val::Int(_) | Val::Big(_) | Val::Float(_) => Val::Int(0),
+        _ => Val::List(Vec::new()),
+    }
+}
+
+/// `value op> variable`, with the variable's current value in `cur`
+fn augment(cur: Val, val: Val, op: BinOp) -> Result<Val, String> {
+    match (cur, val, op) {
+        (a, b, op) if a.is_number() && b.is_number() => arith(op, a, b),
+        // anything can be appended to a list, including another list: ,[1, 2] +> rows
+        (Val::List(mut vec), b, BinOp::Add) => {
+            vec.push(b);
+            Ok(Val::List(vec))
+        }
+        // strings behave like the binary operators: "ab" +> s, 3 *> s
+        (a @ Val::Str(_), b, op) | (a, b @ Val::Str(_), op) => string_op(op, a, b),
+        (a, b, op) => type_mismatch(op, &a, &b),
+    }
+}
+
+fn eval_macro(interp: &mut Interp, name: &str, args: Vec<Val>, expr: &str) -> Result<Val, String> {
+    let def = match MACROS.iter().find(|m| m.name == name) {
+        Some(def) => def,
+        None => return Err(format!("Unknown macro: ${} (line with expression: {})", name, expr)),
+    };
+    let (min, max) = def.args;
+    if args.len() < min || args.len() > max {
+        let expected = if min == max { min.to_string() } else if max == usize::MAX { format!("at least {}", min) } else { format!("{} to {}", min, max) };
+        return Err(format!("Macro ${} takes {} argument(s), got {}", name, expected, args.len()));
+    }
+    match def.run {
+        MacroFn::Values(run) => run(args),
+        MacroFn::Calls(run) => run(interp, args),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Macros: the built-in `$name[...]` functions
+// ---------------------------------------------------------------------------
+
+/// A built-in macro, looked up by name in `MACROS`
+struct MacroDef {
+    name: &'static str,
+    /// smallest and largest number of arguments
+    args: (usize, usize),
+    run: MacroFn,
+}
+
+enum MacroFn {
+    /// works on the argument values alone
+    Values(fn(Vec<Val>) -> Result<Val, String>),
+    /// takes functions as arguments, so it needs the interpreter to call them
+    Calls(fn(&mut Interp, Vec<Val>) -> Result<Val, String>),
+}
+
+/// Every macro, new ones only need an entry here (keep syntax.txt in sync)
+const MACROS: &[MacroDef] = &[
+    MacroDef { name: "s", args: (1, usize::MAX), run: MacroFn::Values(macro_sum) },
+    MacroDef { name: "l", args: (1, 1), run: MacroFn::Values(macro_len) },
+    MacroDef { name: "mn", args: (1, usize::MAX), run: MacroFn::Values(macro_min) },
+    MacroDef { name: "mx", args: (1, usize::MAX), run: MacroFn::Values(macro_max) },
+    MacroDef { name: "p", args: (1, usize::MAX), run: MacroFn::Values(macro_product) },
+    MacroDef { name: "so", args: (1, 1), run: MacroFn::Values(macro_sort) },
+    MacroDef { name: "rv", args: (1, 1), run: MacroFn::Values(macro_reverse) },
+    MacroDef { name: "c", args: (2, 2), run: MacroFn::Values(macro_contains) },
+    MacroDef { name: "i", args: (2, 2), run: MacroFn::Values(macro_index_of) },
+    MacroDef { name: "r", args: (1, 2), run: MacroFn::Values(macro_range) },
+    MacroDef { name: "a", args: (1, 1), run: MacroFn::Values(macro_abs) },
+    MacroDef { name: "d", args: (2, 2), run: MacroFn::Values(macro_divide) },
+    MacroDef { name: "n", args: (1, 1), run: MacroFn::Values(macro_number) },
+    MacroDef { name: "t", args: (1, 1), run: MacroFn::Values(macro_text) },
+    MacroDef { name: "e", args: (1, 1), run: MacroFn::Values(macro_env) },
+    MacroDef { name: "rf", args: (1, 1), run: MacroFn::Values(macro_read_file) },
+    MacroDef { name: "rl", args: (1, 1), run: MacroFn::Values(macro_read_lines) },
+    MacroDef { name: "wf", args: (2, 2), run: MacroFn::Values(macro_write_file) },
+    MacroDef { name: "af", args: (2, 2), run: MacroFn::Values(macro_append_file) },
+    MacroDef { name: "fe", args: (1, 1), run: MacroFn::Values(macro_file_exists) },
+    MacroDef { name: "m", args: (2, 2), run: MacroFn::Calls(macro_map) },
+    MacroDef { name: "f", args: (2, 2), run: MacroFn::Calls(macro_filter) },
+    MacroDef { name: "rd", args: (2, 3), run: MacroFn::Calls(macro_reduce) },
+];
+
+/// The single argument of a one-argument macro
+fn only(args: Vec<Val>) -> Val {
+    args.into_iter().next().unwrap_or(Val::Int(0))
+}
+
+/// Order two values with `<` and `>`, so numbers and strings sort and anything else is a type error
+fn compare(a: &Val, b: &Val) -> Result<Ordering, String> {
+    if binary_op(BinOp::Lt, a.clone(), b.clone())?.is_truthy() {
+        Ok(Ordering::Less)
+    } else if binary_op(BinOp::Gt, a.clone(), b.clone())?.is_truthy() {
+        Ok(Ordering::Greater)
+    } else {
+        Ok(Ordering::Equal)
+    }
+}
+
+/// Elements of a list argument, used by macros that take a list
+fn list_arg(name: &str, v: Val) -> Result<Vec<Val>, String> {
+    match v {
+        Val::List(items) => Ok(items),
+        _ => Err(format!("Macro ${} expects a list, got {} {}", name, v.type_name(), v.repr())),
+    }
+}
+
+/// Sum of a list, or of the arguments when there are several
+fn macro_sum(args: Vec<Val>) -> Result<Val, String> {
+    let val = if args.len() == 1 { only(args) } else { Val::List(args) };
+    match val {
+        // promotes to a big integer or float like `+` does
+        Val::List(items) => items.into_iter().try_fold(Val::Int(0), |total, v| {
+            let v = if v.is_number() { v } else { Val::Int(v.as_i64()) };
+            arith(BinOp::Add, total, v)
+        }),
+        n @ Val::Int(_) | n @ Val::Big(_) | n @ Val::Float(_) => Ok(n),
+        Val::Bool(b) => Err(format!("Cannot sum boolean '{}'", Val::Bool(b).as_string())),
+        Val::Map(_) => Err(format!("Cannot sum map '{}'", val.as_string())),
+        Val::Func(_) => Err(format!("Cannot sum function '{}'", val.as_string())),
+        Val::Str(st) => st.parse::<i64>().map(Val::Int)
+            .map_err(|_| format!("Cannot sum string '{}': not a valid number", st)),
+    }
+}
+
+fn macro_len(args: Vec<Val>) -> Result<Val, String> {
+    let val = only(args);
+    match val {
+        Val::List(items) => Ok(Val::Int(items.len() as i64)),
+        Val::Map(map) => Ok(Val::Int(map.len() as i64)),
+        Val::Str(st) => Ok(Val::Int(st.chars().count() as i64)),
+        Val::Int(_) | Val::Big(_) => Err(format!("Cannot get length of integer '{}'", val.as_string())),
+        Val::Float(f) => Err(format!("Cannot get length of float '{}'", Val::Float(f).as_string())),
+        Val::Bool(b) => Err(format!("Cannot get length of boolean '{}'", Val::Bool(b).as_string())),
+        Val::Func(_) => Err(format!("Cannot get length of function '{}'", val.as_string())),
+    }
+}
+
+/// Smallest or largest of a list, or of the arguments when there are several
+fn pick(name: &str, args: Vec<Val>, want: Ordering) -> Result<Val, String> {
+    let items = if args.len() == 1 { list_arg(name, only(args))? } else { args };
+    let mut iter = items.into_iter();
+    let mut best = iter.next().ok_or_else(|| format!("Macro ${} got an empty list", name))?;
+    for v in iter {
+        if compare(&v, &best)? == want {
+            best = v;
+        }
+    }
+    Ok(best)
+}
+
+fn macro_min(args: Vec<Val>) -> Result<Val, String> {
+    pick("mn", args, Ordering::Less)
+}
+
+fn macro_max(args: Vec<Val>) -> Result<Val, String> {
+    pick("mx", args, Ordering::Greater)
+}
+
+fn macro_product(args: Vec<Val>) -> Result<Val, String> {
+    let items = if args.len() == 1 { list_arg("p", only(args))? } else { args };
+    items.into_iter().try_fold(Val::Int(1), |total, v| binary_op(BinOp::Mul, total, v))
+}
+
+fn macro_sort(args: Vec<Val>) -> Result<Val, String> {
+    let mut items = list_arg("so", only(args))?;
+    // sort_by cannot fail, so remember the first comparison error
+    let mut error = None;
+    items.sort_by(|a, b| compare(a, b).unwrap_or_else(|e| {
+        error.get_or_insert(e);
+        Ordering::Equal
+    }));
+    match error {
+        Some(e) => Err(e),
+        None => Ok(Val::List(items)),
+    }
+}
+
+fn macro_reverse(args: Vec<Val>) -> Result<Val, String> {
+    match only(args) {
+        Val::Str(s) => Ok(Val::Str(s.chars().rev().collect())),
+        v => {
+            let mut items = list_arg("rv", v)?;
+            items.reverse();
+            Ok(Val::List(items))
+        }
+    }
+}
+
+fn macro_contains(args: Vec<Val>) -> Result<Val, String> {
+    contains(&args[0], &args[1]).map(Val::Bool)
+}
+
+/// Position of an element in a list or of a substring in a string, -1 when missing
+fn macro_index_of(args: Vec<Val>) -> Result<Val, String> {
+    let pos = match (&args[0], &args[1]) {
+        (Val::Str(s), Val::Str(sub)) => s.find(sub.as_str()).map(|b| s[..b].chars().count()),
+        (Val::List(items), item) => items.iter().position(|v| matches!(binary_op(BinOp::Eq, v.clone(), item.clone()), Ok(Val::Bool(true)))),
+        (v, _) => return Err(format!("Macro $i expects a list or string, got {} {}", v.type_name(), v.repr())),
+    };
+    Ok(Val::Int(pos.map_or(-1, |p| p as i64)))
+}
+
+/// `$r[n]` is 0 to n-1, `$r[a, b]` is a to b-1
+/// Limit on the length of a `$r` list, the same 1 GiB as a string
+const MAX_RANGE_LEN: usize = MAX_STRING_BYTES / std::mem::size_of::<Val>();
+
+fn macro_range(args: Vec<Val>) -> Result<Val, String> {
+    let bound = |v: &Val| match v {
+        Val::Int(n) => Ok(*n),
+        v => Err(format!("Macro $r expects integers that fit in 64 bits, got {} {}", v.type_name(), v.repr())),
+    };
+    let (start, end) = match args.len() {
+        1 => (0, bound(&args[0])?),
+        _ => (bound(&args[0])?, bound(&args[1])?),
+    };
+    let len = (end as i128 - start as i128).max(0);
+    if len > MAX_RANGE_LEN as i128 {
+        return Err(format!("Overflow: the range {} to {} has too many elements ({})", start, end, len));
+    }
+    Ok(Val::List((start..end).map(Val::Int).collect()))
+}
+
+fn macro_abs(args: Vec<Val>) -> Result<Val, String> {
+    match only(args) {
+        Val::Float(f) => Ok(Val::Float(f.abs())),
+        Val::Big(b) => Ok(BigInt { neg: false, mag: b.mag }.into_val()),
+        Val::Int(n) => Ok(n.checked_abs().map_or_else(|| BigInt::from_i64(n).negate().into_val(), Val::Int)),
+        v => Err(format!("Macro $a expects a number, got {} {}", v.type_name(), v.repr())),
+    }
+}
+
+/// Integer division rounding toward zero, exact for big integers: $d[7, 2] is 3, $d[-7, 2] is -3
+fn macro_divide(args: Vec<Val>) -> Result<Val, String> {
+    let int = |v: &Val| match v {
+        Val::Int(n) => Ok(BigInt::from_i64(*n)),
+        Val::Big(b) => Ok(b.clone()),
+        v => Err(format!("Macro $d expects integers, got {} {}", v.type_name(), v.repr())),
+    };
+    let (a, b) = (int(&args[0])?, int(&args[1])?);
+    if b.mag.is_empty() {
+        return Err("Division by zero".to_string());
+    }
+    Ok(a.div_rem(&b).0.into_val())
+}
+
+/// Convert to a number: strings are parsed, floats are truncated, booleans are 1 or 0
+fn macro_number(args: Vec<Val>) -> Result<Val, String> {
+    match only(args) {
+        Val::Str(s) => {
+            let t = s.trim();
+            let digits = t.trim_start_matches('-');
+            if let Ok(n) = t.parse::<i64>() {
+                Ok(Val::Int(n))
+            } else if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+                let big = BigInt::parse(digits);
+                Ok(if t.starts_with('-') { big.negate() } else { big }.into_val())
+            } else {
+                Err(format!("Cannot convert string \"{}\" to a number", s))
+            }
+        }
+        Val::Float(f) if f.is_finite() => Ok(BigInt::from_f64(f).into_val()),
+        Val::Bool(b) => Ok(Val::Int(b as i64)),
+        n @ Val::Int(_) | n @ Val::Big(_) => Ok(n),
+        v => Err(format!("Cannot convert {} {} to a number", v.type_name(), v.repr())),
+    }
+}
+
+fn macro_text(args: Vec<Val>) -> Result<Val, String> {
+    Ok(Val::Str(only(args).as_string()))
+}
+
+/// The path argument of a file macro
+fn path_arg(name: &str, v: &Val) -> Result<String, String> {
+    match v {
+        Val::Str(path) => Ok(path.clone()),
+        _ => Err(format!("Macro ${} expects a path string, got {} {}", name, v.type_name(), v.repr())),
+    }
+}
+
+fn macro_read_file(args: Vec<Val>) -> Result<Val, String> {
+    let path = path_arg("rf", &args[0])?;
+    fs::read_to_string(&path).map(Val::Str).map_err(|e| format!("Cannot read file '{}': {}", path, e))
+}
+
+fn macro_read_lines(args: Vec<Val>) -> Result<Val, String> {
+    let path = path_arg("rl", &args[0])?;
+    let text = fs::read_to_string(&path).map_err(|e| format!("Cannot read file '{}': {}", path, e))?;
+    Ok(Val::List(text.lines().map(|l| Val::Str(l.to_string())).collect()))
+}
+
+/// Replace the file's contents with the value as text, gives `?+`
+fn macro_write_file(args: Vec<Val>) -> Result<Val, String> {
+    let path = path_arg("wf", &args[0])?;
+    fs::write(&path, args[1].as_string()).map_err(|e| format!("Cannot write file '{}': {}", path, e))?;
+    Ok(Val::Bool(true))
+}
+
+/// Add the value as text to the end of the file, creating it if needed, gives `?+`
+fn macro_append_file(args: Vec<Val>) -> Result<Val, String> {
+    let path = path_arg("af", &args[0])?;
+    fs::OpenOptions::new().create(true).append(true).open(&path)
+        .and_then(|mut f| f.write_all(args[1].as_string().as_bytes()))
+        .map_err(|e| format!("Cannot append to file '{}': {}", path, e))?;
+    Ok(Val::Bool(true))
+}
+
+fn macro_file_exists(args: Vec<Val>) -> Result<Val, String> {
+    let path = path_arg("fe", &args[0])?;
+    Ok(Val::Bool(std::path::Path::new(&path).exists()))
+}
+
+/// Environment variable by name, `?-` when it is not set
+fn macro_env(args: Vec<Val>) -> Result<Val, String> {
+    match only(args) {
+        Val::Str(name) => Ok(std::env::var(&name).map_or(Val::Bool(false), Val::Str)),
+        v => Err(format!("Macro $e expects a variable name string, got {} {}", v.type_name(), v.repr())),
+    }
+}
+
+/// The function argument of a higher-order macro
+fn func_arg(name: &str, v: Val) -> Result<Rc<Closure>, String> {
+    match v {
+        Val::Func(closure) => Ok(closure),
+        _ => Err(format!("Macro ${} expects a function, got {} {}", name, v.type_name(), v.repr())),
+    }
+}
+
+/// f[x] for every element of a list
+fn macro_map(interp: &mut Interp, mut args: Vec<Val>) -> Result<Val, String> {
+    let f = func_arg("m", args.pop().unwrap_or(Val::Int(0)))?;
+    let items = list_arg("m", only(args))?;
+    let mut out = Vec::with_capacity(items.len());
+    for item in items {
+        out.push(interp.invoke(&f, vec![item], &f.label())?);
+    }
+    Ok(Val::List(out))
+}
+
+/// The elements of a list for which f[x] is true
+fn macro_filter(interp: &mut Interp, mut args: Vec<Val>) -> Result<Val, String> {
+    let f = func_arg("f", args.pop().unwrap_or(Val::Int(0)))?;
+    let mut out = Vec::new();
+    for item in list_arg("f", only(args))? {
+        if interp.invoke(&f, vec![item.clone()], &f.label())?.is_truthy() {
+            out.push(item);
+        }
+    }
+    Ok(Val::List(out))
+}
+
+/// Fold a list with f[total, x], starting from the third argument or else the first element
+fn macro_reduce(interp: &mut Interp, args: Vec<Val>) -> Result<Val, String> {
+    let mut args = args.into_iter();
+    let items = list_arg("rd", args.next().unwrap_or(Val::Int(0)))?;
+    let f = func_arg("rd", args.next().unwrap_or(Val::Int(0)))?;
+    let mut items = items.into_iter();
+    let mut total = match args.next() {
+        Some(start) => start,
+        None => items.next().ok_or("Macro $rd got an empty list and no starting value")?,
+    };
+    for item in items {
+        total = interp.invoke(&f, vec![total, item], &f.label())?;
+    }
+    Ok(total)
+}
+
+// ---------------------------------------------------------------------------
+// Big integers: used automatically once a result no longer fits in an i64
+// ---------------------------------------------------------------------------
+
+/// Limbs are base 10^9 so printing needs no division
+const BIG_BASE: u64 = 1_000_000_000;
+
+/// Sign and magnitude, least significant limb first, no trailing zero limbs (zero is empty and not negative)
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BigInt {
+    neg: bool,
+    mag: Vec<u32>,
+}
+
+impl BigInt {
+    fn from_i64(n: i64) -> BigInt {
+        let mut m = n.unsigned_abs();
+        let mut mag = Vec::new();
+        while m > 0 {
+            mag.push((m % BIG_BASE) as u32);
+            m /= BIG_BASE;
+        }
+        BigInt { neg: n < 0, mag }
+    }
+
+    /// Parse a string of decimal digits
+    fn parse(digits: &str) -> BigInt {
+        let mut mag = Vec::new();
+        let mut end = digits.len();
+        while end > 0 {
+            let start = end.saturating_sub(9);
+            mag.push(digits[start..end].parse().unwrap_or(0));
+            end = start;
+        }
+        BigInt { neg: false, mag }.trim()
+    }
+
+    fn to_i64(&self) -> Option<i64> {
+        if self.mag.len() > 3 {
+            return None;
+        }
+        let m = self.mag.iter().rev().fold(0i128, |acc, &d| acc * BIG_BASE as i128 + d as i128);
+        let m = if self.neg { -m } else { m };
+        if m >= i64::MIN as i128 && m <= i64::MAX as i128 { Some(m as i64) } else { None }
+    }
+
+    /// Integer part of a finite float, exact past the i64 range too
+    fn from_f64(f: f64) -> BigInt {
+        let f = f.trunc();
+        if f.abs() < 9.0e18 {
+            return BigInt::from_i64(f as i64);
+        }
+        // a float this large is a whole number: its 53-bit mantissa times a power of two
+        let bits = f.to_bits();
+        let exp = ((bits >> 52) & 0x7ff) as u32 - 1075;
+        let mant = (bits & ((1 << 52) - 1)) | (1 << 52);
+        let m = BigInt::from_i64(mant as i64).mul(&BigInt::from_i64(2).pow(exp));
+        if f < 0.0 { m.negate() } else { m }
+    }
+
+    fn to_f64(&self) -> f64 {
+        let m = self.mag.iter().rev().fold(0.0, |acc, &d| acc * BIG_BASE as f64 + d as f64);
+        if self.neg { -m } else { m }
+    }
+
+    /// Back to a plain Int when the value fits
+    fn into_val(self) -> Val {
+        match self.to_i64() {
+            Some(n) => Val::Int(n),
+            None => Val::Big(self),
+        }
+    }
+
+    fn trim(mut self) -> BigInt {
+        while self.mag.last() == Some(&0) {
+            self.mag.pop();
+        }
+        if self.mag.is_empty() {
+            self.neg = false;
+        }
+        self
+    }
+
+    fn negate(&self) -> BigInt {
+        BigInt { neg: !self.neg, mag: self.mag.clone() }.trim()
+    }
+
+    fn add(&self, other: &BigInt) -> BigInt {
+        if self.neg == other.neg {
+            return BigInt { neg: self.neg, mag: add_mag(&self.mag, &other.mag) }.trim();
+        }
+        // different signs: subtract the smaller magnitude from the larger
+        match cmp_mag(&self.mag, &other.mag) {
+            Ordering::Less => BigInt { neg: other.neg, mag: sub_mag(&other.mag, &self.mag) }.trim(),
+            _ => BigInt { neg: self.neg, mag: sub_mag(&self.mag, &other.mag) }.trim(),
+        }
+    }
+
+    fn sub(&self, other: &BigInt) -> BigInt {
+        self.add(&other.negate())
+    }
+
+    fn mul(&self, other: &BigInt) -> BigInt {
+        let mut out = vec![0u64; self.mag.len() + other.mag.len() + 1];
+        for (i, &a) in self.mag.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, &b) in other.mag.iter().enumerate() {
+                let cur = out[i + j] + a as u64 * b as u64 + carry;
+                out[i + j] = cur % BIG_BASE;
+                carry = cur / BIG_BASE;
+            }
+            let mut k = i + other.mag.len();
+            while carry > 0 {
+                let cur = out[k] + carry;
+                out[k] = cur % BIG_BASE;
+                carry = cur / BIG_BASE;
+                k += 1;
+            }
+        }
+        BigInt { neg: self.neg != other.neg, mag: out.into_iter().map(|d| d as u32).collect() }.trim()
+    }
+
+    /// Truncating division like i64: the quotient rounds toward zero, the remainder takes the dividend's sign
+    fn div_rem(&self, other: &BigInt) -> (BigInt, BigInt) {
+        let mut quot = vec![0u32; self.mag.len()];
+        let mut rem: Vec<u32> = Vec::new();
+        for i in (0..self.mag.len()).rev() {
+            rem.insert(0, self.mag[i]);
+            rem = BigInt { neg: false, mag: rem }.trim().mag;
+            // binary search for the largest digit q with other * q <= rem
+            let (mut lo, mut hi) = (0u64, BIG_BASE - 1);
+            while lo < hi {
+                let mid = (lo + hi + 1) / 2;
+                if cmp_mag(&mul_small(&other.mag, mid), &rem) == Ordering::Greater { hi = mid - 1; } else { lo = mid; }
+            }
+            quot[i] = lo as u32;
+            rem = sub_mag(&rem, &mul_small(&other.mag, lo));
+        }
+        (
+            BigInt { neg: self.neg != other.neg, mag: quot }.trim(),
+            BigInt { neg: self.neg, mag: rem }.trim(),
+        )
+    }
+
+    fn pow(&self, mut exp: u32) -> BigInt {
+        let mut base = self.clone();
+        let mut out = BigInt::from_i64(1);
+        while exp > 0 {
+            if exp & 1 == 1 {
+                out = out.mul(&base);
+            }
+            exp >>= 1;
+            if exp > 0 {
+                base = base.mul(&base);
+            }
+        }
+        out
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.neg, other.neg) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_mag(&self.mag, &other.mag),
+            (true, true) => cmp_mag(&other.mag, &self.mag),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut limbs = self.mag.iter().rev();
+        match limbs.next() {
+            None => return write!(f, "0"),
+            Some(top) => write!(f, "{}{}", if self.neg { "-" } else { "" }, top)?,
+        }
+        for limb in limbs {
+            write!(f, "{:09}", limb)?;
+        }
+        Ok(())
+    }
+}
+
+fn cmp_mag(a: &[u32], b: &[u32]) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0u64;
+    for i in 0..a.len().max(b.len()) {
+        let cur = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        out.push((cur % BIG_BASE) as u32);
+        carry = cur / BIG_BASE;
+    }
+    if carry > 0 {
+        out.push(carry as u32);
+    }
+    out
+}
+
+/// `a - b` for magnitudes with a >= b
+fn sub_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for i in 0..a.len() {
+        let mut cur = a[i] as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = (cur < 0) as i64;
+        if cur < 0 {
+            cur += BIG_BASE as i64;
+        }
+        out.push(cur as u32);
+    }
+    BigInt { neg: false, mag: out }.trim().mag
+}
+
+fn mul_small(a: &[u32], m: u64) -> Vec<u32> {
+    BigInt { neg: false, mag: a.to_vec() }.mul(&BigInt::from_i64(m as i64)).mag
 }